@@ -10,9 +10,7 @@ use actix_web::{
     middleware::{self, Compat},
     App, HttpServer,
 };
-use actix_web_middleware_keycloak_auth::{
-    AlwaysReturnPolicy, DecodingKey, KeycloakAuth, Role, StandardKeycloakClaims,
-};
+use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth, Role, StandardKeycloakClaims};
 use paperclip::actix::{api_v2_operation, web, OpenApiExt};
 
 const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----
@@ -63,12 +61,12 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(|| {
         let keycloak_auth = KeycloakAuth {
-            detailed_responses: true,
-            passthrough_policy: AlwaysReturnPolicy,
-            keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
             required_roles: vec![Role::Realm {
                 role: "test".to_owned(),
             }],
+            ..KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            )
         };
 
         App::new()
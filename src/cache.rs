@@ -0,0 +1,314 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use chrono::{DateTime, Utc};
+use futures_util::future::{ready, LocalBoxFuture};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A cache of token validation results, so that the same token is not verified (or introspected) on every request
+///
+/// Only the claims of valid tokens are stored, until the token expires (`exp` claim) at most: tokens without an `exp` claim are not cached.
+/// Entries are keyed by a SHA-256 hash of the token (and not by its `jti` claim, that cannot be trusted before the token is validated).
+///
+/// Implementations can use a store that is shared between servers (the current time is provided by the [Clock](super::Clock) of the middleware).
+///
+/// **A cache (or a shared store) must only be used by middlewares that validate tokens the same way** (same keys, issuers, introspection endpoint, ...).
+/// Keys do not depend on the settings of the middleware: a token that was validated by a middleware would be accepted by any other middleware using the same cache, without being verified.
+/// Use a separate cache (or a separate prefix in a shared store) for each configuration.
+///
+/// When tokens are introspected, a cached result also hides the revocation of the token by the authorization server (for example by a logout) until the entry expires.
+pub trait ValidationCache: Send + Sync {
+    /// Get the claims of a validated token (if they are cached and have not expired)
+    fn get(&self, key: &str, now: DateTime<Utc>) -> LocalBoxFuture<'static, Option<Value>>;
+
+    /// Store the claims of a validated token, until the provided expiration date (at most)
+    fn insert(
+        &self,
+        key: &str,
+        claims: Value,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> LocalBoxFuture<'static, ()>;
+}
+
+/// Key of a token in a [ValidationCache](ValidationCache) (the SHA-256 hash of the token, as hexadecimal)
+pub(crate) fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A bounded in-memory [ValidationCache](ValidationCache)
+///
+/// Entries expire after a maximal duration (or when the token expires, if it is earlier).
+/// When the cache is full, the entry that expires first is evicted, and expired entries are regularly removed (every time the maximal duration elapses).
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{InMemoryValidationCache, KeycloakAuth, DecodingKey};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+/// let cache = Arc::new(InMemoryValidationCache::new(10_000, Duration::from_secs(60)));
+/// let keycloak_auth = KeycloakAuth {
+///     validation_cache: Some(cache.clone()),
+///     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+/// };
+///
+/// let stats = cache.stats();
+/// println!("hits: {}, misses: {}", stats.hits, stats.misses);
+/// ```
+#[derive(Debug)]
+pub struct InMemoryValidationCache {
+    max_entries: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Keys of the entries, in the order they expire
+    by_expiration: BTreeSet<(DateTime<Utc>, String)>,
+    last_sweep: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    claims: Value,
+    expires_at: DateTime<Utc>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_expiration
+                .remove(&(entry.expires_at, key.to_owned()));
+        }
+    }
+
+    /// Remove the entry that expires first
+    fn remove_first(&mut self) {
+        if let Some((_, key)) = self.by_expiration.pop_first() {
+            self.by_key.remove(&key);
+        }
+    }
+
+    /// Remove the entries that have expired
+    fn sweep(&mut self, now: DateTime<Utc>) {
+        while self
+            .by_expiration
+            .first()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            self.remove_first();
+        }
+        self.last_sweep = Some(now);
+    }
+}
+
+/// Counters of a [InMemoryValidationCache](InMemoryValidationCache)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of lookups that found valid claims
+    pub hits: u64,
+    /// Number of lookups that found nothing (or expired claims)
+    pub misses: u64,
+    /// Number of entries currently stored (including expired ones that were not removed yet)
+    pub entries: usize,
+}
+
+impl InMemoryValidationCache {
+    /// Create a cache that holds at most `max_entries` entries, each of them for at most `ttl`
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the counters of the cache
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock_entries().by_key.len(),
+        }
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, Entries> {
+        // The entries stay consistent even if a thread panicked while holding the lock
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lookup(&self, key: &str, now: DateTime<Utc>) -> Option<Value> {
+        let mut entries = self.lock_entries();
+        let claims = match entries.by_key.get(key) {
+            Some(entry) if entry.expires_at > now => Some(entry.claims.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let counter = if claims.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        claims
+    }
+
+    fn store(&self, key: &str, claims: Value, now: DateTime<Utc>, expires_at: DateTime<Utc>) {
+        let max_expires_at = chrono::Duration::from_std(self.ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .unwrap_or(expires_at);
+        let expires_at = expires_at.min(max_expires_at);
+        if expires_at <= now || self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.lock_entries();
+        let sweep_interval = chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::MAX);
+        if entries
+            .last_sweep
+            .is_none_or(|last_sweep| now - last_sweep >= sweep_interval)
+        {
+            entries.sweep(now);
+        }
+
+        if entries.by_key.contains_key(key) {
+            entries.remove(key);
+        } else if entries.by_key.len() >= self.max_entries {
+            entries.remove_first();
+        }
+        entries.by_expiration.insert((expires_at, key.to_owned()));
+        entries
+            .by_key
+            .insert(key.to_owned(), Entry { claims, expires_at });
+    }
+}
+
+impl ValidationCache for InMemoryValidationCache {
+    fn get(&self, key: &str, now: DateTime<Utc>) -> LocalBoxFuture<'static, Option<Value>> {
+        Box::pin(ready(self.lookup(key, now)))
+    }
+
+    fn insert(
+        &self,
+        key: &str,
+        claims: Value,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> LocalBoxFuture<'static, ()> {
+        self.store(key, claims, now, expires_at);
+        Box::pin(ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn hash_of_tokens() {
+        assert_eq!(
+            token_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn expiration() {
+        let cache = InMemoryValidationCache::new(10, Duration::from_secs(60));
+
+        // Bounded by the expiration date of the token
+        cache.store("a", json!("a"), at(0), at(30));
+        assert_eq!(cache.lookup("a", at(29)), Some(json!("a")));
+        assert_eq!(cache.lookup("a", at(30)), None);
+
+        // Bounded by the TTL of the cache
+        cache.store("b", json!("b"), at(0), at(3600));
+        assert_eq!(cache.lookup("b", at(59)), Some(json!("b")));
+        assert_eq!(cache.lookup("b", at(60)), None);
+
+        // Already expired
+        cache.store("c", json!("c"), at(0), at(-1));
+        assert_eq!(cache.lookup("c", at(0)), None);
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 3,
+                entries: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = InMemoryValidationCache::new(2, Duration::from_secs(60));
+
+        cache.store("a", json!("a"), at(0), at(20));
+        cache.store("b", json!("b"), at(0), at(10));
+        cache.store("c", json!("c"), at(0), at(30));
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.lookup("b", at(0)), None);
+        assert_eq!(cache.lookup("a", at(0)), Some(json!("a")));
+
+        // Expired entries are removed first
+        cache.store("d", json!("d"), at(25), at(40));
+        assert_eq!(cache.lookup("a", at(25)), None);
+        assert_eq!(cache.lookup("c", at(25)), Some(json!("c")));
+        assert_eq!(cache.lookup("d", at(25)), Some(json!("d")));
+
+        // Replacing an entry does not evict another one
+        cache.store("d", json!("d2"), at(25), at(40));
+        assert_eq!(cache.lookup("c", at(25)), Some(json!("c")));
+        assert_eq!(cache.lookup("d", at(25)), Some(json!("d2")));
+        cache.store("d", json!("d3"), at(25), at(28));
+        cache.store("e", json!("e"), at(25), at(40));
+        assert_eq!(cache.lookup("d", at(25)), None);
+        assert_eq!(cache.lookup("c", at(25)), Some(json!("c")));
+        assert_eq!(cache.lookup("e", at(25)), Some(json!("e")));
+    }
+
+    #[test]
+    fn sweep() {
+        let cache = InMemoryValidationCache::new(10, Duration::from_secs(60));
+
+        cache.store("a", json!("a"), at(0), at(10));
+        cache.store("b", json!("b"), at(0), at(50));
+        cache.store("c", json!("c"), at(30), at(90));
+        assert_eq!(cache.stats().entries, 3);
+
+        // Expired entries are only removed once the maximal duration has elapsed since the last sweep
+        cache.store("d", json!("d"), at(60), at(100));
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.lookup("c", at(60)), Some(json!("c")));
+        assert_eq!(cache.lookup("d", at(60)), Some(json!("d")));
+    }
+}
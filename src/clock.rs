@@ -0,0 +1,95 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+/// Something that gives the current time, used to validate the time claims of JWT (`exp`, `nbf` and `iat`)
+///
+/// A clock can be defined using a closure:
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::Clock;
+/// use chrono::{DateTime, TimeZone, Utc};
+///
+/// let clock = || Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+/// # fn check<C: Clock>(_: C) {}
+/// # check(clock);
+/// ```
+pub trait Clock: Send + Sync {
+    /// Get the current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A clock can be defined using a closure
+impl<F> Clock for F
+where
+    F: Fn() -> DateTime<Utc> + Send + Sync,
+{
+    fn now(&self) -> DateTime<Utc> {
+        self()
+    }
+}
+
+/// The clock of the system (the default)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stays at a given time until it is changed (useful for tests)
+///
+/// Clones of a `FixedClock` share the same time.
+#[derive(Debug, Clone)]
+pub struct FixedClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl FixedClock {
+    /// Create a clock that is stopped at the provided time
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Set the time of the clock
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    /// Move the time of the clock forward (or backward, if the duration is negative)
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn fixed_clock() {
+        let clock = FixedClock::new(Utc.timestamp_opt(1_600_000_000, 0).unwrap());
+        let other = clock.clone();
+
+        clock.advance(Duration::seconds(30));
+        assert_eq!(other.now().timestamp(), 1_600_000_030);
+
+        other.set(Utc.timestamp_opt(1_500_000_000, 0).unwrap());
+        assert_eq!(clock.now().timestamp(), 1_500_000_000);
+    }
+}
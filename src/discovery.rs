@@ -0,0 +1,121 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use jsonwebtoken::Algorithm;
+use log::debug;
+use serde::Deserialize;
+use std::str::FromStr;
+
+use super::http::HttpClient;
+
+/// OpenID Connect provider metadata, as served by Keycloak at `/realms/{realm}/.well-known/openid-configuration`
+///
+/// Only the fields that are useful to this crate are extracted.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProviderMetadata {
+    /// Issuer (the URL of the realm)
+    pub issuer: String,
+    /// URL of the JWKS document that contains the public keys of the realm
+    pub jwks_uri: String,
+    /// Algorithms that the realm can use to sign tokens
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    /// URL of the token introspection endpoint (if any, see [Introspection](super::Introspection))
+    pub introspection_endpoint: Option<String>,
+}
+
+impl ProviderMetadata {
+    /// Fetch the provider metadata of an issuer (for example `https://sso.example.com/realms/my-realm`)
+    ///
+    /// The `issuer` of the returned metadata must match the requested issuer.
+    pub async fn discover<C: HttpClient + ?Sized>(
+        issuer_url: &str,
+        client: &C,
+    ) -> Result<Self, DiscoveryError> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer_url.trim_end_matches('/')
+        );
+        debug!(
+            "Fetching OpenID Connect provider metadata from {}",
+            &discovery_url
+        );
+
+        let document = client
+            .get(&discovery_url)
+            .await
+            .map_err(DiscoveryError::Fetch)?;
+        let metadata = serde_json::from_str::<Self>(&document).map_err(DiscoveryError::Parse)?;
+
+        if metadata.issuer.trim_end_matches('/') != issuer_url.trim_end_matches('/') {
+            return Err(DiscoveryError::IssuerMismatch(metadata.issuer));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Asymmetric signing algorithms supported by the provider (unknown and symmetric algorithms are ignored, because JWT are verified using public keys)
+    pub fn signing_algorithms(&self) -> Vec<Algorithm> {
+        self.id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| Algorithm::from_str(alg).ok())
+            .filter(|alg| !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+            .collect()
+    }
+}
+
+/// An error that happened while discovering the configuration of an OpenID Connect provider
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// The discovery document cannot be fetched
+    Fetch(String),
+    /// The discovery document cannot be parsed
+    Parse(serde_json::Error),
+    /// The issuer of the discovery document does not match the requested issuer
+    IssuerMismatch(String),
+}
+
+impl std::fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fetch(e) => write!(f, "Cannot fetch OpenID Connect discovery document ({})", e),
+            Self::Parse(e) => write!(f, "Cannot parse OpenID Connect discovery document ({})", e),
+            Self::IssuerMismatch(issuer) => write!(
+                f,
+                "OpenID Connect discovery document has an unexpected issuer ({})",
+                issuer
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signing_algorithms() {
+        let metadata = ProviderMetadata {
+            issuer: "https://sso.example.com/realms/test".to_owned(),
+            jwks_uri: "https://sso.example.com/realms/test/protocol/openid-connect/certs"
+                .to_owned(),
+            id_token_signing_alg_values_supported: vec![
+                "PS384".to_owned(),
+                "RS256".to_owned(),
+                "HS256".to_owned(),
+                "ES256".to_owned(),
+                "none".to_owned(),
+            ],
+            introspection_endpoint: None,
+        };
+
+        assert_eq!(
+            metadata.signing_algorithms(),
+            vec![Algorithm::PS384, Algorithm::RS256, Algorithm::ES256]
+        );
+    }
+}
@@ -6,31 +6,82 @@
 use actix_web::body::BoxBody;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
 
-use super::Role;
+use super::permissions::PermissionRequirement;
+use super::roles::RoleExpr;
+use super::Algorithm;
 
 /// An authentication error
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AuthError {
-    /// The `Authorization` header is missing
-    NoAuthorizationHeader,
-    /// The value of the `Authorization` header is not `Bearer [JWT]`
+    /// No JWT was found in the request (the `Authorization` header is missing, by default), with the list of token sources that were tried
+    NoAuthorizationHeader(Vec<String>),
+    /// The value of the `Authorization` header is not `Bearer [JWT]` (or the header was sent several times)
     InvalidAuthorizationHeader,
+    /// The `Authorization` header uses another scheme than `Bearer` (for example `Basic`)
+    WrongAuthorizationScheme(String),
     /// The JWT is invalid (bad structure, wrong signature, ...)
     InvalidJwt(String),
+    /// No key matching the key ID (`kid`) of the JWT was found
+    UnknownKeyId(Option<String>),
+    /// The keys used to verify JWT cannot be loaded (the JWKS endpoint cannot be reached, or sent an invalid document)
+    KeySetUnavailable(String),
+    /// The JWT is signed with an algorithm (`alg` header) that is not allowed
+    AlgorithmNotAllowed(Algorithm),
     /// The JWT does not contain expected claims
     DecodeError(String),
+    /// The JWT has expired (`exp` claim)
+    ExpiredJwt,
+    /// The JWT is not valid yet (`nbf` claim)
+    NotYetValidJwt,
+    /// The JWT was issued too long ago (`iat` claim), or its issuance date is missing while a maximum age is configured
+    TooOldJwt,
+    /// The token is not active, according to the introspection endpoint (it was revoked, it has expired, ...)
+    InactiveToken,
+    /// The token cannot be introspected (the introspection endpoint cannot be reached, rejected the client credentials, or sent an invalid response)
+    IntrospectionFailed(String),
+    /// The token was revoked (according to the revocation checker), with the reason why
+    Revoked(String),
+    /// The issuer (`iss` claim) of the JWT is missing or is not accepted
+    InvalidIssuer(Option<String>),
+    /// The audience (`aud` claim) of the JWT is missing or does not include any of the accepted audiences
+    InvalidAudience(Vec<String>),
     /// The JWT contains role claims that does not have the expected type/structure
     RoleParsingError(String),
-    /// The JWT does not contain some required roles
-    MissingRoles(Vec<Role>),
+    /// The roles of the JWT do not satisfy the required roles, with the part of the requirement that is not satisfied
+    MissingRoles(RoleExpr),
+    /// The JWT is not a member of some required groups (or of one of their subgroups)
+    MissingGroups(Vec<String>),
+    /// The JWT does not contain some required OAuth scopes (`scope` claim)
+    MissingScopes(Vec<String>),
+    /// The JWT (a RPT, from Keycloak Authorization Services) does not grant some required permissions (`authorization.permissions` claim)
+    MissingPermissions(Vec<PermissionRequirement>),
+    /// A per-route check (like [RoleCheck](super::RoleCheck)) was used for a request that was not handled by a [KeycloakAuth](super::KeycloakAuth) middleware (it must be wrapped by one)
+    MiddlewareNotConfigured,
+    /// The claims of the JWT were rejected by a custom validator (see [ClaimValidator](super::ClaimValidator)), with the code of the error and a human-readable description
+    Custom {
+        /// Code of the error (see [code](AuthError::code))
+        code: String,
+        /// Human-readable description of the error
+        message: String,
+    },
 }
 
 impl ResponseError for AuthError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::MissingRoles(_) => StatusCode::FORBIDDEN,
+            Self::MissingRoles(_)
+            | Self::MissingGroups(_)
+            | Self::MissingScopes(_)
+            | Self::MissingPermissions(_)
+            | Self::Custom { .. } => StatusCode::FORBIDDEN,
             Self::InvalidAuthorizationHeader => StatusCode::BAD_REQUEST,
+            Self::MiddlewareNotConfigured => StatusCode::INTERNAL_SERVER_ERROR,
+            // The token may be valid: the authorization server could not tell
+            Self::IntrospectionFailed(_) | Self::KeySetUnavailable(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             _ => StatusCode::UNAUTHORIZED,
         }
     }
@@ -43,12 +94,53 @@ impl ResponseError for AuthError {
 impl std::fmt::Display for AuthError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::NoAuthorizationHeader => f.write_str("No bearer token was provided"),
+            Self::NoAuthorizationHeader(sources) => write!(
+                f,
+                "No bearer token was provided (tried: {})",
+                sources.join(", ")
+            ),
             Self::InvalidAuthorizationHeader => {
-                f.write_str("Authorization header value is invalid (cannot convert it into string)")
+                f.write_str("Authorization header value is invalid (expected: Bearer [JWT])")
             }
+            Self::WrongAuthorizationScheme(scheme) => write!(
+                f,
+                "Authorization header uses an unsupported scheme ({}, expected: Bearer)",
+                scheme
+            ),
             Self::InvalidJwt(e) => write!(f, "Invalid JWT token ({})", e),
+            Self::UnknownKeyId(Some(kid)) => {
+                write!(f, "No key was found to verify JWT token (kid: {})", kid)
+            }
+            Self::UnknownKeyId(None) => f.write_str("No key was found to verify JWT token"),
+            Self::KeySetUnavailable(e) => {
+                write!(f, "Keys to verify JWT token are unavailable ({})", e)
+            }
+            Self::AlgorithmNotAllowed(alg) => {
+                write!(
+                    f,
+                    "JWT token is signed using a forbidden algorithm ({:?})",
+                    alg
+                )
+            }
             Self::DecodeError(e) => write!(f, "Error while decoding JWT token ({})", e),
+            Self::ExpiredJwt => f.write_str("JWT token has expired"),
+            Self::NotYetValidJwt => f.write_str("JWT token is not valid yet"),
+            Self::TooOldJwt => f.write_str("JWT token was issued too long ago"),
+            Self::InactiveToken => f.write_str("Token is not active"),
+            Self::IntrospectionFailed(e) => write!(f, "Error while introspecting token ({})", e),
+            Self::Revoked(reason) => write!(f, "Token has been revoked ({})", reason),
+            Self::InvalidIssuer(Some(iss)) => {
+                write!(f, "JWT token has an invalid issuer ({})", iss)
+            }
+            Self::InvalidIssuer(None) => f.write_str("JWT token has no issuer"),
+            Self::InvalidAudience(audiences) if audiences.is_empty() => {
+                f.write_str("JWT token has no audience")
+            }
+            Self::InvalidAudience(audiences) => write!(
+                f,
+                "JWT token has an invalid audience ({})",
+                audiences.join(", ")
+            ),
             Self::RoleParsingError(e) => write!(
                 f,
                 "Error while parsing Keycloak roles from JWT token ({})",
@@ -59,25 +151,212 @@ impl std::fmt::Display for AuthError {
                     f,
                     "JWT token is missing roles: {}",
                     &roles
+                        .requirements()
                         .iter()
                         .map(|r| r.to_string())
                         .collect::<Vec<String>>()
                         .join(", ")
                 )
             }
+            Self::MissingGroups(groups) => {
+                write!(f, "JWT token is missing groups: {}", groups.join(", "))
+            }
+            Self::MissingScopes(scopes) => {
+                write!(f, "JWT token is missing scopes: {}", scopes.join(", "))
+            }
+            Self::MissingPermissions(permissions) => write!(
+                f,
+                "JWT token is missing permissions: {}",
+                permissions
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Self::MiddlewareNotConfigured => f.write_str(
+                "No authentication middleware handled the request (KeycloakAuth must wrap routes that check roles or permissions)",
+            ),
+            Self::Custom { message, .. } => f.write_str(message),
         }
     }
 }
 
 impl AuthError {
+    /// Create a custom error (for example from a [ClaimValidator](super::ClaimValidator)), with a code and a human-readable description
+    pub fn custom(code: &str, message: &str) -> Self {
+        Self::Custom {
+            code: code.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
     /// Build a HTTP response from an authentication error
     pub fn to_response(&self, detailed_responses: bool) -> HttpResponse {
+        self.plain_text_response(self.status_code(), detailed_responses)
+    }
+
+    pub(crate) fn plain_text_response(
+        &self,
+        status: StatusCode,
+        detailed_responses: bool,
+    ) -> HttpResponse {
         if detailed_responses {
-            self.error_response()
+            HttpResponse::new(status).set_body(BoxBody::new(self.to_string()))
+        } else {
+            HttpResponse::build(status).body(status.to_string())
+        }
+    }
+
+    /// Build a HTTP response from an authentication error, with a `application/problem+json` body (see [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807))
+    ///
+    /// When responses are detailed, the body contains the code of the error (see [code](AuthError::code)), a human-readable description, and the missing roles, groups, scopes or permissions (if any).
+    /// Otherwise, it only contains the HTTP status and the correlation ID.
+    pub fn to_problem_response(
+        &self,
+        detailed_responses: bool,
+        correlation_id: &str,
+    ) -> HttpResponse {
+        self.problem_response(self.status_code(), detailed_responses, correlation_id)
+    }
+
+    pub(crate) fn problem_response(
+        &self,
+        status: StatusCode,
+        detailed_responses: bool,
+        correlation_id: &str,
+    ) -> HttpResponse {
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "correlation_id": correlation_id,
+        });
+        if detailed_responses {
+            problem["type"] = json!(format!("{}{}", PROBLEM_TYPE_PREFIX, self.code()));
+            problem["code"] = json!(self.code());
+            problem["detail"] = json!(self.to_string());
+            if let Self::MissingGroups(groups) = self {
+                problem["missing_groups"] = json!(groups);
+            }
+            if let Self::MissingScopes(scopes) = self {
+                problem["missing_scopes"] = json!(scopes);
+            }
+            if let Self::MissingPermissions(permissions) = self {
+                problem["missing_permissions"] = json!(permissions);
+            }
+            if let Self::MissingRoles(roles) = self {
+                problem["missing_roles"] = json!(roles
+                    .requirements()
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>());
+            }
+        }
+
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .body(problem.to_string())
+    }
+
+    /// Build the value of the `WWW-Authenticate` header that describes this error (see [RFC 6750](https://www.rfc-editor.org/rfc/rfc6750#section-3))
+    ///
+    /// The description of the error (and the missing scopes, if any) is only included when responses are detailed.
+    pub fn www_authenticate(&self, realm: Option<&str>, detailed_responses: bool) -> String {
+        let mut params = vec![];
+        if let Some(realm) = realm {
+            params.push(format!("realm=\"{}\"", escape_quoted_string(realm)));
+        }
+        let error = match self {
+            // The client did not try to authenticate (using the Bearer scheme): no error code must be sent
+            Self::NoAuthorizationHeader(_) | Self::WrongAuthorizationScheme(_) => None,
+            // The token was not found to be invalid: the error is on the server side
+            Self::IntrospectionFailed(_)
+            | Self::KeySetUnavailable(_)
+            | Self::MiddlewareNotConfigured => None,
+            Self::InvalidAuthorizationHeader => Some("invalid_request"),
+            Self::MissingRoles(_)
+            | Self::MissingGroups(_)
+            | Self::MissingScopes(_)
+            | Self::MissingPermissions(_)
+            | Self::Custom { .. } => Some("insufficient_scope"),
+            _ => Some("invalid_token"),
+        };
+        if let Some(error) = error {
+            params.push(format!("error=\"{}\"", error));
+            if detailed_responses {
+                params.push(format!(
+                    "error_description=\"{}\"",
+                    escape_quoted_string(&self.to_string())
+                ));
+                if let Self::MissingScopes(scopes) = self {
+                    params.push(format!(
+                        "scope=\"{}\"",
+                        escape_quoted_string(&scopes.join(" "))
+                    ));
+                }
+            }
+        }
+
+        if params.is_empty() {
+            "Bearer".to_owned()
         } else {
-            HttpResponse::build(self.status_code()).body(self.status_code().to_string())
+            format!("Bearer {}", params.join(", "))
         }
     }
+
+    /// A stable code that identifies the kind of error (it does not change between versions, unlike the human-readable descriptions)
+    pub fn code(&self) -> &str {
+        match self {
+            Self::NoAuthorizationHeader(_) => "no_authorization_header",
+            Self::InvalidAuthorizationHeader => "invalid_authorization_header",
+            Self::WrongAuthorizationScheme(_) => "wrong_authorization_scheme",
+            Self::InvalidJwt(_) => "invalid_jwt",
+            Self::UnknownKeyId(_) => "unknown_key_id",
+            Self::KeySetUnavailable(_) => "key_set_unavailable",
+            Self::AlgorithmNotAllowed(_) => "algorithm_not_allowed",
+            Self::DecodeError(_) => "decode_error",
+            Self::ExpiredJwt => "expired_jwt",
+            Self::NotYetValidJwt => "not_yet_valid_jwt",
+            Self::TooOldJwt => "too_old_jwt",
+            Self::InactiveToken => "inactive_token",
+            Self::IntrospectionFailed(_) => "introspection_failed",
+            Self::Revoked(_) => "revoked",
+            Self::InvalidIssuer(_) => "invalid_issuer",
+            Self::InvalidAudience(_) => "invalid_audience",
+            Self::RoleParsingError(_) => "role_parsing_error",
+            Self::MissingRoles(_) => "missing_roles",
+            Self::MissingGroups(_) => "missing_groups",
+            Self::MissingScopes(_) => "missing_scopes",
+            Self::MissingPermissions(_) => "missing_permissions",
+            Self::MiddlewareNotConfigured => "middleware_not_configured",
+            Self::Custom { code, .. } => code,
+        }
+    }
+}
+
+/// Escape a string so that it can be used as a quoted string in an HTTP header (non-ASCII characters are dropped because they are not allowed in header values)
+fn escape_quoted_string(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii() && !c.is_ascii_control())
+        .flat_map(|c| match c {
+            '"' | '\\' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+/// Prefix of the `type` URI of problem+json error responses (followed by the code of the error)
+const PROBLEM_TYPE_PREFIX: &str = "urn:actix-web-middleware-keycloak-auth:error:";
+
+/// Format of the HTTP responses that are sent when authentication fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseFormat {
+    /// A plain text body (the default)
+    #[default]
+    PlainText,
+    /// A JSON body following [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) (`application/problem+json`)
+    ProblemJson,
 }
 
 /// An error that happened while trying to extract and parse an unstructured claim
@@ -97,3 +376,261 @@ impl std::fmt::Display for ClaimError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Role;
+    use actix_web::body::to_bytes;
+    use serde_json::{from_slice, Value};
+
+    async fn problem(error: &AuthError, detailed_responses: bool) -> (StatusCode, Value) {
+        let res = error.to_problem_response(detailed_responses, "correlation-id");
+        let status = res.status();
+        assert_eq!(
+            res.headers().get("Content-Type").unwrap(),
+            "application/problem+json"
+        );
+        let body = to_bytes(res.into_body()).await.unwrap();
+        (status, from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn problem_json_per_variant() {
+        let cases = [
+            (
+                AuthError::NoAuthorizationHeader(vec!["Authorization header".to_owned()]),
+                401,
+                "no_authorization_header",
+                json!({}),
+            ),
+            (
+                AuthError::InvalidAuthorizationHeader,
+                400,
+                "invalid_authorization_header",
+                json!({}),
+            ),
+            (
+                AuthError::WrongAuthorizationScheme("Basic".to_owned()),
+                401,
+                "wrong_authorization_scheme",
+                json!({}),
+            ),
+            (
+                AuthError::InvalidJwt("bad".to_owned()),
+                401,
+                "invalid_jwt",
+                json!({}),
+            ),
+            (
+                AuthError::UnknownKeyId(Some("kid".to_owned())),
+                401,
+                "unknown_key_id",
+                json!({}),
+            ),
+            (
+                AuthError::AlgorithmNotAllowed(Algorithm::HS256),
+                401,
+                "algorithm_not_allowed",
+                json!({}),
+            ),
+            (
+                AuthError::DecodeError("bad".to_owned()),
+                401,
+                "decode_error",
+                json!({}),
+            ),
+            (AuthError::ExpiredJwt, 401, "expired_jwt", json!({})),
+            (
+                AuthError::NotYetValidJwt,
+                401,
+                "not_yet_valid_jwt",
+                json!({}),
+            ),
+            (AuthError::TooOldJwt, 401, "too_old_jwt", json!({})),
+            (
+                AuthError::InvalidIssuer(None),
+                401,
+                "invalid_issuer",
+                json!({}),
+            ),
+            (
+                AuthError::InvalidAudience(vec![]),
+                401,
+                "invalid_audience",
+                json!({}),
+            ),
+            (
+                AuthError::RoleParsingError("bad".to_owned()),
+                401,
+                "role_parsing_error",
+                json!({}),
+            ),
+            (AuthError::InactiveToken, 401, "inactive_token", json!({})),
+            (
+                AuthError::IntrospectionFailed("timeout".to_owned()),
+                503,
+                "introspection_failed",
+                json!({}),
+            ),
+            (
+                AuthError::KeySetUnavailable("timeout".to_owned()),
+                503,
+                "key_set_unavailable",
+                json!({}),
+            ),
+            (
+                AuthError::MiddlewareNotConfigured,
+                500,
+                "middleware_not_configured",
+                json!({}),
+            ),
+            (
+                AuthError::Revoked("the token was revoked".to_owned()),
+                401,
+                "revoked",
+                json!({}),
+            ),
+            (
+                AuthError::MissingRoles(RoleExpr::realm("admin")),
+                403,
+                "missing_roles",
+                json!({ "missing_roles": ["admin"] }),
+            ),
+            (
+                AuthError::MissingGroups(vec!["/org".to_owned()]),
+                403,
+                "missing_groups",
+                json!({ "missing_groups": ["/org"] }),
+            ),
+            (
+                AuthError::MissingScopes(vec!["orders:read".to_owned()]),
+                403,
+                "missing_scopes",
+                json!({ "missing_scopes": ["orders:read"] }),
+            ),
+            (
+                AuthError::MissingPermissions(vec![PermissionRequirement::resource("orders")]),
+                403,
+                "missing_permissions",
+                json!({ "missing_permissions": [{ "resource": "orders", "scope": null }] }),
+            ),
+            (
+                AuthError::custom("wrong_tenant", "JWT token belongs to another tenant"),
+                403,
+                "wrong_tenant",
+                json!({}),
+            ),
+        ];
+
+        for (error, status, code, extra) in cases {
+            let (res_status, body) = problem(&error, true).await;
+            assert_eq!(res_status.as_u16(), status);
+            assert_eq!(error.code(), code);
+            let mut expected = json!({
+                "type": format!("urn:actix-web-middleware-keycloak-auth:error:{}", code),
+                "title": res_status.canonical_reason().unwrap(),
+                "status": status,
+                "code": code,
+                "detail": error.to_string(),
+                "correlation_id": "correlation-id",
+            });
+            for (key, value) in extra.as_object().unwrap() {
+                expected[key] = value.clone();
+            }
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn problem_json_missing_roles() {
+        let error = AuthError::MissingRoles(
+            vec![
+                Role::Realm {
+                    role: "admin".to_owned(),
+                },
+                Role::Client {
+                    client: "backoffice".to_owned(),
+                    role: "readonly".to_owned(),
+                },
+            ]
+            .into(),
+        );
+
+        let (status, body) = problem(&error, true).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            body,
+            json!({
+                "type": "urn:actix-web-middleware-keycloak-auth:error:missing_roles",
+                "title": "Forbidden",
+                "status": 403,
+                "code": "missing_roles",
+                "detail": "JWT token is missing roles: admin, backoffice.readonly",
+                "missing_roles": ["admin", "backoffice.readonly"],
+                "correlation_id": "correlation-id",
+            })
+        );
+
+        let (status, body) = problem(&error, false).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Forbidden",
+                "status": 403,
+                "correlation_id": "correlation-id",
+            })
+        );
+    }
+
+    #[test]
+    fn www_authenticate() {
+        assert_eq!(
+            AuthError::NoAuthorizationHeader(vec![]).www_authenticate(Some("my-realm"), true),
+            r#"Bearer realm="my-realm""#
+        );
+        assert_eq!(
+            AuthError::NoAuthorizationHeader(vec![]).www_authenticate(None, true),
+            "Bearer"
+        );
+        assert_eq!(
+            AuthError::InvalidAuthorizationHeader.www_authenticate(None, false),
+            r#"Bearer error="invalid_request""#
+        );
+        assert_eq!(
+            AuthError::InvalidJwt("bad \"token\"".to_owned())
+                .www_authenticate(Some("my-realm"), true),
+            r#"Bearer realm="my-realm", error="invalid_token", error_description="Invalid JWT token (bad \"token\")""#
+        );
+        assert_eq!(
+            AuthError::ExpiredJwt.www_authenticate(Some("my-realm"), false),
+            r#"Bearer realm="my-realm", error="invalid_token""#
+        );
+        assert_eq!(
+            AuthError::MissingRoles(
+                vec![Role::Realm {
+                    role: "admin".to_owned()
+                }]
+                .into()
+            )
+            .www_authenticate(Some("my-realm"), true),
+            r#"Bearer realm="my-realm", error="insufficient_scope", error_description="JWT token is missing roles: admin""#
+        );
+        assert_eq!(
+            AuthError::MissingScopes(vec!["orders:read".to_owned(), "orders:write".to_owned()])
+                .www_authenticate(None, true),
+            r#"Bearer error="insufficient_scope", error_description="JWT token is missing scopes: orders:read, orders:write", scope="orders:read orders:write""#
+        );
+        assert_eq!(
+            AuthError::MissingScopes(vec!["orders:read".to_owned()]).www_authenticate(None, false),
+            r#"Bearer error="insufficient_scope""#
+        );
+        assert_eq!(
+            AuthError::IntrospectionFailed("timeout".to_owned())
+                .www_authenticate(Some("my-realm"), true),
+            r#"Bearer realm="my-realm""#
+        );
+    }
+}
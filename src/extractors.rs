@@ -3,19 +3,29 @@
 // Copyright: 2020, David Sferruzza
 // License: MIT
 
+use actix_web::dev::ServiceRequest;
+use actix_web::error::InternalError;
 use actix_web::{FromRequest, HttpMessage, ResponseError};
 use futures_util::future::{ready, Ready};
+use log::debug;
 use serde::de::DeserializeOwned;
 use std::fmt::Display;
+use std::marker::PhantomData;
 use std::ops::Deref;
 
-use super::{RawClaims, Role, StandardClaims, UnstructuredClaims};
+use super::guard::check_request_roles;
+use super::render::render_after_middleware;
+use super::roles::RoleRequirement;
+use super::{Group, Permission, RawClaims, Role, StandardClaims, UnstructuredClaims};
 
 #[derive(Debug)]
 pub enum KeycloakExtractorError {
     ClaimsExtraction,
     Claims(serde_json::Error),
     RolesExtraction,
+    GroupsExtraction,
+    ScopesExtraction,
+    PermissionsExtraction,
 }
 
 impl Display for KeycloakExtractorError {
@@ -24,6 +34,11 @@ impl Display for KeycloakExtractorError {
             Self::ClaimsExtraction => write!(f, "Could not find JWT claims in ReqData"),
             Self::Claims(e) => write!(f, "Error while deserializing JWT: {}", e),
             Self::RolesExtraction => write!(f, "Could not find Keycloak roles in ReqData"),
+            Self::GroupsExtraction => write!(f, "Could not find Keycloak groups in ReqData"),
+            Self::ScopesExtraction => write!(f, "Could not find OAuth scopes in ReqData"),
+            Self::PermissionsExtraction => {
+                write!(f, "Could not find Keycloak permissions in ReqData")
+            }
         }
     }
 }
@@ -34,6 +49,9 @@ impl ResponseError for KeycloakExtractorError {
             Self::ClaimsExtraction => actix_web::http::StatusCode::FORBIDDEN,
             Self::Claims(_) => actix_web::http::StatusCode::FORBIDDEN,
             Self::RolesExtraction => actix_web::http::StatusCode::FORBIDDEN,
+            Self::GroupsExtraction => actix_web::http::StatusCode::FORBIDDEN,
+            Self::ScopesExtraction => actix_web::http::StatusCode::FORBIDDEN,
+            Self::PermissionsExtraction => actix_web::http::StatusCode::FORBIDDEN,
         }
     }
 }
@@ -124,3 +142,194 @@ impl FromRequest for KeycloakRoles {
         }
     }
 }
+
+/// Actix Web extractor for Keycloak groups (from the `groups` claim)
+#[derive(Debug, Clone)]
+pub struct KeycloakGroups(Vec<Group>);
+
+impl KeycloakGroups {
+    /// Consumes the `KeycloakGroups`, returning its wrapped data
+    pub fn into_inner(self) -> Vec<Group> {
+        self.0
+    }
+
+    /// Check if the user is a member of a group or of one of its subgroups (see [Group::is_in](Group::is_in))
+    pub fn is_member_of(&self, path: &str) -> bool {
+        self.0.iter().any(|group| group.is_in(path))
+    }
+}
+
+impl Deref for KeycloakGroups {
+    type Target = Vec<Group>;
+
+    fn deref(&self) -> &Vec<Group> {
+        &self.0
+    }
+}
+
+impl FromRequest for KeycloakGroups {
+    type Error = KeycloakExtractorError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let req_data = req.extensions();
+        match req_data.get::<Vec<Group>>() {
+            Some(groups) => ready(Ok(Self(groups.to_owned()))),
+            None => ready(Err(KeycloakExtractorError::GroupsExtraction)),
+        }
+    }
+}
+
+/// Actix Web extractor for OAuth scopes (from the `scope` claim)
+#[derive(Debug, Clone)]
+pub struct KeycloakScopes(Vec<String>);
+
+impl KeycloakScopes {
+    pub(crate) fn new(scopes: Vec<String>) -> Self {
+        Self(scopes)
+    }
+
+    /// Consumes the `KeycloakScopes`, returning its wrapped data
+    pub fn into_inner(self) -> Vec<String> {
+        self.0
+    }
+
+    /// Check if a scope was granted
+    pub fn has(&self, scope: &str) -> bool {
+        self.0.iter().any(|s| s == scope)
+    }
+}
+
+impl Deref for KeycloakScopes {
+    type Target = Vec<String>;
+
+    fn deref(&self) -> &Vec<String> {
+        &self.0
+    }
+}
+
+impl FromRequest for KeycloakScopes {
+    type Error = KeycloakExtractorError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let req_data = req.extensions();
+        match req_data.get::<Self>() {
+            Some(scopes) => ready(Ok(scopes.to_owned())),
+            None => ready(Err(KeycloakExtractorError::ScopesExtraction)),
+        }
+    }
+}
+
+/// Actix Web extractor for permissions granted by Keycloak Authorization Services (from the `authorization.permissions` claim of RPT)
+#[derive(Debug, Clone)]
+pub struct KeycloakPermissions(Vec<Permission>);
+
+impl KeycloakPermissions {
+    /// Consumes the `KeycloakPermissions`, returning its wrapped data
+    pub fn into_inner(self) -> Vec<Permission> {
+        self.0
+    }
+
+    /// Check if a permission was granted on a resource (identified by its name or ID), with a scope (if any)
+    pub fn has(&self, resource: &str, scope: Option<&str>) -> bool {
+        self.0
+            .iter()
+            .any(|permission| permission.grants(resource, scope))
+    }
+}
+
+impl Deref for KeycloakPermissions {
+    type Target = Vec<Permission>;
+
+    fn deref(&self) -> &Vec<Permission> {
+        &self.0
+    }
+}
+
+impl FromRequest for KeycloakPermissions {
+    type Error = KeycloakExtractorError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let req_data = req.extensions();
+        match req_data.get::<Vec<Permission>>() {
+            Some(permissions) => ready(Ok(Self(permissions.to_owned()))),
+            None => ready(Err(KeycloakExtractorError::PermissionsExtraction)),
+        }
+    }
+}
+
+/// Actix Web extractor that requires Keycloak roles (described by `R`, see [RoleRequirement](RoleRequirement)), and gives access to them
+///
+/// When roles are missing, extraction fails with a [MissingRoles](super::AuthError::MissingRoles) error, rendered like the errors of the middleware.
+pub struct RequireRoles<R: RoleRequirement> {
+    roles: Vec<Role>,
+    requirement: PhantomData<R>,
+}
+
+impl<R: RoleRequirement> RequireRoles<R> {
+    /// Consumes the `RequireRoles`, returning the roles extracted from the JWT
+    pub fn into_inner(self) -> Vec<Role> {
+        self.roles
+    }
+}
+
+impl<R: RoleRequirement> Deref for RequireRoles<R> {
+    type Target = Vec<Role>;
+
+    fn deref(&self) -> &Vec<Role> {
+        &self.roles
+    }
+}
+
+impl<R: RoleRequirement> Clone for RequireRoles<R> {
+    fn clone(&self) -> Self {
+        Self {
+            roles: self.roles.clone(),
+            requirement: PhantomData,
+        }
+    }
+}
+
+impl<R: RoleRequirement> std::fmt::Debug for RequireRoles<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RequireRoles").field(&self.roles).finish()
+    }
+}
+
+impl<R: RoleRequirement> FromRequest for RequireRoles<R> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let result = check_request_roles(&req.extensions(), &R::role_requirement());
+        ready(match result {
+            Ok(()) => Ok(Self {
+                roles: req
+                    .extensions()
+                    .get::<Vec<Role>>()
+                    .cloned()
+                    .unwrap_or_default(),
+                requirement: PhantomData,
+            }),
+            Err(e) => {
+                debug!("{}", &e);
+                let res = render_after_middleware(&e, &ServiceRequest::from_request(req.clone()));
+                Err(InternalError::from_response(e, res).into())
+            }
+        })
+    }
+}
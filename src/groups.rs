@@ -0,0 +1,143 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::errors::AuthError;
+
+/// A Keycloak group, identified by its path (for example `/org/team-a`), as found in the `groups` claim
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Group(String);
+
+impl Group {
+    /// Create a group from its path
+    pub fn new(path: &str) -> Self {
+        Self(path.to_owned())
+    }
+
+    /// Path of the group
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+
+    /// Names of the groups from the top-level group to this group (for example `["org", "team-a"]`)
+    pub fn segments(&self) -> Vec<&str> {
+        segments(&self.0)
+    }
+
+    /// Check if the group is the provided group or one of its subgroups
+    ///
+    /// Paths are compared segment by segment (so `/org/team-a` is in `/org`, but `/organization` is not), and the leading slash is optional.
+    /// An empty path (like `/`) is not a group, so no group is in it.
+    pub fn is_in(&self, ancestor: &str) -> bool {
+        let ancestor = segments(ancestor);
+        let segments = self.segments();
+        !ancestor.is_empty()
+            && segments.len() >= ancestor.len()
+            && segments[..ancestor.len()] == ancestor[..]
+    }
+}
+
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+/// Parse the value of a `groups` claim (a missing or null claim means that there are no groups)
+pub fn parse_groups(groups: Option<&Value>) -> Result<Vec<Group>, String> {
+    match groups {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(groups) => Vec::<Group>::deserialize(groups).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn check_groups(groups: &[Group], required_groups: &[String]) -> Result<(), AuthError> {
+    debug!(
+        "JWT contains groups: {}",
+        &groups
+            .iter()
+            .map(|g| g.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    let missing_groups: Vec<String> = required_groups
+        .iter()
+        .filter(|required| !groups.iter().any(|group| group.is_in(required)))
+        .cloned()
+        .collect();
+
+    if missing_groups.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::MissingGroups(missing_groups))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtree() {
+        let group = Group::new("/org/team-a");
+
+        assert_eq!(group.segments(), vec!["org", "team-a"]);
+        assert!(group.is_in("/org/team-a"));
+        assert!(group.is_in("/org"));
+        assert!(group.is_in("org/"));
+        assert!(!group.is_in("/"));
+        assert!(!group.is_in(""));
+        assert!(!group.is_in("/org/team-a/sub"));
+        assert!(!group.is_in("/org/team"));
+        assert!(!group.is_in("/other"));
+    }
+
+    #[test]
+    fn groups_claim() {
+        assert_eq!(
+            parse_groups(Some(&serde_json::json!(["/org/team-a", "/admins"]))),
+            Ok(vec![Group::new("/org/team-a"), Group::new("/admins")])
+        );
+        assert_eq!(parse_groups(None), Ok(vec![]));
+        assert_eq!(parse_groups(Some(&Value::Null)), Ok(vec![]));
+        assert!(parse_groups(Some(&serde_json::json!("/org"))).is_err());
+        assert!(parse_groups(Some(&serde_json::json!([{ "path": "/org" }]))).is_err());
+    }
+
+    #[test]
+    fn required_groups() {
+        let groups = &[Group::new("/org/team-a"), Group::new("/admins")];
+
+        assert!(check_groups(groups, &[]).is_ok());
+        assert!(check_groups(groups, &["/org".to_owned(), "/admins".to_owned()]).is_ok());
+        assert_eq!(
+            check_groups(
+                groups,
+                &[
+                    "/org/team-b".to_owned(),
+                    "/org".to_owned(),
+                    "/organization".to_owned()
+                ]
+            ),
+            Err(AuthError::MissingGroups(vec![
+                "/org/team-b".to_owned(),
+                "/organization".to_owned()
+            ]))
+        );
+        assert_eq!(
+            check_groups(groups, &["/".to_owned()]),
+            Err(AuthError::MissingGroups(vec!["/".to_owned()]))
+        );
+    }
+}
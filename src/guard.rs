@@ -0,0 +1,253 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{self, Extensions, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use log::{debug, trace};
+use std::rc::Rc;
+
+use super::errors::AuthError;
+use super::permissions::{check_permissions, Permission, PermissionRequirement};
+use super::render::render_after_middleware;
+use super::roles::{check_role_expr, RoleExpr};
+use super::{KeycloakAuthStatus, Role};
+
+/// A guard that matches requests whose JWT roles satisfy a role expression
+///
+/// It reads the roles that were extracted by a [KeycloakAuth](super::KeycloakAuth) middleware, so it must be used inside a scope that is wrapped by one.
+/// Requests that do not match are routed elsewhere (usually to a 404 error): use [RoleCheck](RoleCheck) to respond with a 403 error instead.
+///
+/// ```
+/// use actix_web::{web, HttpResponse};
+/// use actix_web_middleware_keycloak_auth::{RoleExpr, RoleGuard};
+///
+/// let route = web::get()
+///     .guard(RoleGuard::new(RoleExpr::realm("admin")))
+///     .to(|| async { HttpResponse::Ok().body("Admin") });
+/// ```
+#[derive(Debug, Clone)]
+pub struct RoleGuard {
+    /// Roles that must be included in JWT
+    pub required_roles: RoleExpr,
+}
+
+impl RoleGuard {
+    /// Create a guard that requires the provided roles (a [RoleExpr](RoleExpr) or a `Vec<Role>`)
+    pub fn new<R: Into<RoleExpr>>(required_roles: R) -> Self {
+        Self {
+            required_roles: required_roles.into(),
+        }
+    }
+}
+
+impl Guard for RoleGuard {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        ctx.req_data()
+            .get::<Vec<Role>>()
+            .is_some_and(|roles| self.required_roles.is_satisfied_by(roles))
+    }
+}
+
+/// A middleware that requires the roles of JWT to satisfy a role expression, for a single route, resource or scope
+///
+/// It reads the roles that were extracted by a [KeycloakAuth](super::KeycloakAuth) middleware, so it must be wrapped by one.
+/// When roles are missing, it responds with a [MissingRoles](super::AuthError::MissingRoles) error, rendered like the errors of the `KeycloakAuth` middleware.
+/// When authentication failed but the passthrough policy let the request through, it responds with the authentication error.
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RoleCheck, RoleExpr};
+///
+/// # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+/// let keycloak_auth = KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+///
+/// App::new().service(
+///     web::scope("/private")
+///         .wrap(keycloak_auth)
+///         .route("", web::get().to(|| async { HttpResponse::Ok().body("Private") }))
+///         .service(
+///             web::resource("/admin")
+///                 .wrap(RoleCheck::new(RoleExpr::realm("admin")))
+///                 .route(web::get().to(|| async { HttpResponse::Ok().body("Admin") })),
+///         ),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RoleCheck {
+    /// Roles that must be included in JWT
+    pub required_roles: RoleExpr,
+}
+
+impl RoleCheck {
+    /// Create a middleware that requires the provided roles (a [RoleExpr](RoleExpr) or a `Vec<Role>`)
+    pub fn new<R: Into<RoleExpr>>(required_roles: R) -> Self {
+        Self {
+            required_roles: required_roles.into(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RoleCheck
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequirementCheckMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        trace!("Initialize RequirementCheckMiddleware (roles)");
+        let required_roles = self.required_roles.clone();
+        ok(RequirementCheckMiddleware {
+            service: Rc::new(service),
+            check: Rc::new(move |extensions| check_request_roles(extensions, &required_roles)),
+        })
+    }
+}
+
+/// A middleware that requires JWT to grant permissions (from Keycloak Authorization Services), for a single route, resource or scope
+///
+/// It reads the permissions that were extracted by a [KeycloakAuth](super::KeycloakAuth) middleware, so it must be wrapped by one.
+/// When permissions are missing, it responds with a [MissingPermissions](super::AuthError::MissingPermissions) error, rendered like the errors of the `KeycloakAuth` middleware.
+/// When authentication failed but the passthrough policy let the request through, it responds with the authentication error.
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, PermissionCheck, PermissionRequirement};
+///
+/// # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+/// let keycloak_auth = KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+///
+/// App::new().service(
+///     web::scope("/orders")
+///         .wrap(keycloak_auth)
+///         .service(
+///             web::resource("")
+///                 .wrap(PermissionCheck::new(vec![PermissionRequirement::resource_scope("orders", "read")]))
+///                 .route(web::get().to(|| async { HttpResponse::Ok().body("Orders") })),
+///         ),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct PermissionCheck {
+    /// Permissions that must be granted by JWT
+    pub required_permissions: Vec<PermissionRequirement>,
+}
+
+impl PermissionCheck {
+    /// Create a middleware that requires the provided permissions
+    pub fn new(required_permissions: Vec<PermissionRequirement>) -> Self {
+        Self {
+            required_permissions,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for PermissionCheck
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequirementCheckMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        trace!("Initialize RequirementCheckMiddleware (permissions)");
+        let required_permissions = self.required_permissions.clone();
+        ok(RequirementCheckMiddleware {
+            service: Rc::new(service),
+            check: Rc::new(move |extensions| {
+                check_request_permissions(extensions, &required_permissions)
+            }),
+        })
+    }
+}
+
+/// Check of request-local data performed by a [RequirementCheckMiddleware](RequirementCheckMiddleware)
+type RequirementCheckFn = dyn Fn(&Extensions) -> Result<(), AuthError>;
+
+/// Internal middleware that checks requirements (roles or permissions) per route, resource or scope
+pub struct RequirementCheckMiddleware<S> {
+    service: Rc<S>,
+    check: Rc<RequirementCheckFn>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirementCheckMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let check = Rc::clone(&self.check);
+
+        Box::pin(async move {
+            let result = check(&req.extensions());
+            match result {
+                Ok(()) => service.call(req).await.map(|res| res.map_into_left_body()),
+                Err(e) => {
+                    debug!("{}", &e);
+                    let res = render_after_middleware(&e, &req);
+                    Ok(req.into_response(res.map_into_right_body()))
+                }
+            }
+        })
+    }
+}
+
+/// Check the roles that were extracted by the middleware (if authentication failed but the request was let through, the authentication error is returned)
+///
+/// Requests that were not handled by the middleware are rejected, so that a missing middleware does not let everything through.
+pub(crate) fn check_request_roles(
+    extensions: &Extensions,
+    required_roles: &RoleExpr,
+) -> Result<(), AuthError> {
+    match (
+        extensions.get::<Vec<Role>>(),
+        extensions.get::<KeycloakAuthStatus>(),
+    ) {
+        (None, Some(KeycloakAuthStatus::Failure(e))) => Err(e.clone()),
+        (None, None) => Err(AuthError::MiddlewareNotConfigured),
+        (roles, _) => check_role_expr(roles.map(Vec::as_slice).unwrap_or_default(), required_roles),
+    }
+}
+
+/// Check the permissions that were extracted by the middleware (if authentication failed but the request was let through, the authentication error is returned)
+///
+/// Requests that were not handled by the middleware are rejected, so that a missing middleware does not let everything through.
+fn check_request_permissions(
+    extensions: &Extensions,
+    required_permissions: &[PermissionRequirement],
+) -> Result<(), AuthError> {
+    match (
+        extensions.get::<Vec<Permission>>(),
+        extensions.get::<KeycloakAuthStatus>(),
+    ) {
+        (None, Some(KeycloakAuthStatus::Failure(e))) => Err(e.clone()),
+        (None, None) => Err(AuthError::MiddlewareNotConfigured),
+        (permissions, _) => check_permissions(
+            permissions.map(Vec::as_slice).unwrap_or_default(),
+            required_permissions,
+        ),
+    }
+}
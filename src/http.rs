@@ -0,0 +1,128 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use futures_util::future::{ready, LocalBoxFuture};
+use std::future::Future;
+use std::sync::Arc;
+#[cfg(feature = "http_client")]
+use std::time::Duration;
+
+/// An HTTP client, used to get documents from Keycloak (discovery document, JWKS, ...) and to call its endpoints (token introspection)
+///
+/// This is what allows the HTTP transport to be swapped (for example to use a stub in tests).
+/// A client can be defined using a closure that takes an URL and returns a future of the response body:
+///
+/// ```
+/// let client = |url: &str| {
+///     let body = format!(r#"{{"requested_url":"{}"}}"#, url);
+///     async move { Ok::<_, String>(body) }
+/// };
+/// # fn check<C: actix_web_middleware_keycloak_auth::HttpClient>(_: C) {}
+/// # check(client);
+/// ```
+pub trait HttpClient: Send + Sync {
+    /// Send a GET request, and return the body of the response (or an error if the request failed or the status is not a success)
+    fn get(&self, url: &str) -> LocalBoxFuture<'static, Result<String, String>>;
+
+    /// Send a POST request with a form body (`application/x-www-form-urlencoded`), and return the body of the response (or an error if the request failed or the status is not a success)
+    ///
+    /// Clients that only support GET requests (like the ones defined using a closure) do not need to implement it: by default, it fails.
+    fn post_form(
+        &self,
+        url: &str,
+        _form: &[(&str, &str)],
+    ) -> LocalBoxFuture<'static, Result<String, String>> {
+        Box::pin(ready(Err(format!(
+            "This HTTP client cannot send POST requests ({})",
+            url
+        ))))
+    }
+}
+
+/// An HTTP client can be defined using a closure
+impl<F, Fut> HttpClient for F
+where
+    F: Fn(&str) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<String, String>> + 'static,
+{
+    fn get(&self, url: &str) -> LocalBoxFuture<'static, Result<String, String>> {
+        Box::pin(self(url))
+    }
+}
+
+impl<C: HttpClient + ?Sized> HttpClient for Arc<C> {
+    fn get(&self, url: &str) -> LocalBoxFuture<'static, Result<String, String>> {
+        self.as_ref().get(url)
+    }
+
+    fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+    ) -> LocalBoxFuture<'static, Result<String, String>> {
+        self.as_ref().post_form(url, form)
+    }
+}
+
+/// Default HTTP client (based on `reqwest`)
+///
+/// Requests fail if they take longer than a timeout ([DEFAULT_TIMEOUT](DefaultHttpClient::DEFAULT_TIMEOUT) by default), so that requests to the middleware do not wait forever for an unresponsive Keycloak.
+#[cfg(feature = "http_client")]
+#[derive(Debug, Clone)]
+pub struct DefaultHttpClient {
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "http_client")]
+impl DefaultHttpClient {
+    /// Timeout of requests, unless another one is configured
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Create a client whose requests fail if they take longer than the provided timeout (from connecting to reading the response body)
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("cannot initialize HTTP client"),
+        }
+    }
+}
+
+#[cfg(feature = "http_client")]
+impl Default for DefaultHttpClient {
+    fn default() -> Self {
+        Self::with_timeout(Self::DEFAULT_TIMEOUT)
+    }
+}
+
+#[cfg(feature = "http_client")]
+impl HttpClient for DefaultHttpClient {
+    fn get(&self, url: &str) -> LocalBoxFuture<'static, Result<String, String>> {
+        let request = self.client.get(url).send();
+        Box::pin(async move {
+            let response = request
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())
+        })
+    }
+
+    fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+    ) -> LocalBoxFuture<'static, Result<String, String>> {
+        let request = self.client.post(url).form(form).send();
+        Box::pin(async move {
+            let response = request
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())
+        })
+    }
+}
@@ -0,0 +1,186 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use log::debug;
+use serde_json::Value;
+use std::sync::Arc;
+
+use super::errors::AuthError;
+#[cfg(feature = "http_client")]
+use super::http::DefaultHttpClient;
+use super::http::HttpClient;
+
+/// Settings of token introspection (see [RFC 7662](https://www.rfc-editor.org/rfc/rfc7662))
+///
+/// When it is enabled, tokens are not verified offline: they are sent to the introspection endpoint of the realm, that tells whether they are still active (so that tokens revoked by a logout are rejected before they expire).
+/// The introspection endpoint requires the credentials of a confidential client.
+#[derive(Clone)]
+pub struct Introspection {
+    /// URL of the introspection endpoint (`{issuer}/protocol/openid-connect/token/introspect` for Keycloak)
+    pub endpoint: String,
+    /// ID of the client used to call the introspection endpoint
+    pub client_id: String,
+    /// Secret of the client used to call the introspection endpoint
+    pub client_secret: String,
+    /// HTTP client used to call the introspection endpoint
+    pub client: Arc<dyn HttpClient>,
+}
+
+impl Introspection {
+    /// Introspect tokens using the provided endpoint and client credentials, and the default HTTP client
+    #[cfg(feature = "http_client")]
+    pub fn new(endpoint: &str, client_id: &str, client_secret: &str) -> Self {
+        Self::with_client(
+            endpoint,
+            client_id,
+            client_secret,
+            DefaultHttpClient::default(),
+        )
+    }
+
+    /// Introspect tokens using the provided endpoint and client credentials, and the provided HTTP client
+    pub fn with_client<C: HttpClient + 'static>(
+        endpoint: &str,
+        client_id: &str,
+        client_secret: &str,
+        client: C,
+    ) -> Self {
+        Self {
+            endpoint: endpoint.to_owned(),
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.to_owned(),
+            client: Arc::new(client),
+        }
+    }
+
+    /// URL of the introspection endpoint of a Keycloak realm (for example `https://sso.example.com/realms/my-realm`)
+    pub fn keycloak_endpoint(issuer_url: &str) -> String {
+        format!(
+            "{}/protocol/openid-connect/token/introspect",
+            issuer_url.trim_end_matches('/')
+        )
+    }
+
+    /// Introspect a token, and return the introspection response (the claims of the token) if it is active
+    pub(crate) async fn introspect(&self, token: &str) -> Result<Value, AuthError> {
+        debug!("Introspecting token using {}", &self.endpoint);
+        let response = self
+            .client
+            .post_form(
+                &self.endpoint,
+                &[
+                    ("token", token),
+                    ("token_type_hint", "access_token"),
+                    ("client_id", &self.client_id),
+                    ("client_secret", &self.client_secret),
+                ],
+            )
+            .await
+            .map_err(AuthError::IntrospectionFailed)?;
+        let response = serde_json::from_str::<Value>(&response)
+            .map_err(|e| AuthError::IntrospectionFailed(e.to_string()))?;
+
+        match response.get("active").and_then(Value::as_bool) {
+            Some(true) => Ok(response),
+            Some(false) => Err(AuthError::InactiveToken),
+            None => Err(AuthError::IntrospectionFailed(
+                "the response has no active field".to_owned(),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Debug for Introspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Introspection")
+            .field("endpoint", &self.endpoint)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::{ready, LocalBoxFuture};
+    use serde_json::json;
+
+    /// An HTTP client that answers with a fixed response, after checking the form it gets
+    struct StubClient(Result<String, String>);
+
+    impl HttpClient for StubClient {
+        fn get(&self, url: &str) -> LocalBoxFuture<'static, Result<String, String>> {
+            Box::pin(ready(Err(format!("Unexpected GET request ({})", url))))
+        }
+
+        fn post_form(
+            &self,
+            url: &str,
+            form: &[(&str, &str)],
+        ) -> LocalBoxFuture<'static, Result<String, String>> {
+            assert_eq!(
+                url,
+                "https://sso.example.com/realms/test/protocol/openid-connect/token/introspect"
+            );
+            assert_eq!(
+                form,
+                &[
+                    ("token", "abc"),
+                    ("token_type_hint", "access_token"),
+                    ("client_id", "api"),
+                    ("client_secret", "secret"),
+                ]
+            );
+            Box::pin(ready(self.0.clone()))
+        }
+    }
+
+    fn introspection(response: Result<String, String>) -> Introspection {
+        Introspection::with_client(
+            &Introspection::keycloak_endpoint("https://sso.example.com/realms/test/"),
+            "api",
+            "secret",
+            StubClient(response),
+        )
+    }
+
+    #[actix_web::test]
+    async fn introspect() {
+        let response = json!({ "active": true, "sub": "user" });
+        assert_eq!(
+            introspection(Ok(response.to_string()))
+                .introspect("abc")
+                .await,
+            Ok(response)
+        );
+
+        assert_eq!(
+            introspection(Ok(r#"{"active":false}"#.to_owned()))
+                .introspect("abc")
+                .await,
+            Err(AuthError::InactiveToken)
+        );
+        assert!(matches!(
+            introspection(Ok(r#"{"sub":"user"}"#.to_owned()))
+                .introspect("abc")
+                .await,
+            Err(AuthError::IntrospectionFailed(_))
+        ));
+        assert!(matches!(
+            introspection(Ok("not json".to_owned()))
+                .introspect("abc")
+                .await,
+            Err(AuthError::IntrospectionFailed(_))
+        ));
+        assert_eq!(
+            introspection(Err("401 Unauthorized".to_owned()))
+                .introspect("abc")
+                .await,
+            Err(AuthError::IntrospectionFailed(
+                "401 Unauthorized".to_owned()
+            ))
+        );
+    }
+}
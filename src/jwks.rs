@@ -0,0 +1,398 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::DecodingKey;
+use log::debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[cfg(feature = "http_client")]
+use super::http::DefaultHttpClient;
+use super::http::HttpClient;
+use super::keys::{KeyError, KeySet};
+
+/// Something that can fetch a JWKS document (for example from the `/realms/{realm}/protocol/openid-connect/certs` endpoint of Keycloak)
+///
+/// This is what allows the HTTP transport to be swapped.
+/// A fetcher can be defined using a closure that returns a future of the JWKS document:
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{KeyError, RemoteKeySet};
+///
+/// let key_set = RemoteKeySet::new(|| async { Ok::<_, KeyError>(r#"{"keys":[]}"#.to_owned()) });
+/// ```
+pub trait JwksFetcher: Send + Sync {
+    /// Fetch the JWKS document (as JSON)
+    fn fetch(&self) -> LocalBoxFuture<'static, Result<String, KeyError>>;
+}
+
+/// A JWKS fetcher can be defined using a closure
+impl<F, Fut> JwksFetcher for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<String, KeyError>> + 'static,
+{
+    fn fetch(&self) -> LocalBoxFuture<'static, Result<String, KeyError>> {
+        Box::pin(self())
+    }
+}
+
+/// JWKS fetcher that uses an HTTP client to get the JWKS document from an URL
+#[derive(Clone)]
+pub struct HttpJwksFetcher {
+    url: String,
+    client: Arc<dyn HttpClient>,
+}
+
+impl HttpJwksFetcher {
+    /// Create a fetcher for the provided JWKS URL, that uses the default HTTP client
+    #[cfg(feature = "http_client")]
+    pub fn new<U: Into<String>>(url: U) -> Self {
+        Self::with_client(url, DefaultHttpClient::default())
+    }
+
+    /// Create a fetcher for the provided JWKS URL, that uses the provided HTTP client
+    pub fn with_client<U: Into<String>, C: HttpClient + 'static>(url: U, client: C) -> Self {
+        Self {
+            url: url.into(),
+            client: Arc::new(client),
+        }
+    }
+}
+
+impl JwksFetcher for HttpJwksFetcher {
+    fn fetch(&self) -> LocalBoxFuture<'static, Result<String, KeyError>> {
+        let request = self.client.get(&self.url);
+        Box::pin(async move { request.await.map_err(KeyError::Fetch) })
+    }
+}
+
+/// Settings of a [RemoteKeySet](RemoteKeySet)
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteKeySetConfig {
+    /// Once keys are older than this, they are refreshed in background
+    pub refresh_interval: Duration,
+    /// Minimal duration between two fetches of the JWKS document (this limits how often a JWT with an unknown key ID can trigger a fetch)
+    pub min_refetch_interval: Duration,
+}
+
+impl Default for RemoteKeySetConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_secs(5 * 60),
+            min_refetch_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A set of keys that is fetched from a JWKS endpoint, and kept up to date
+///
+/// Keys are fetched when the first JWT is verified.
+/// Then, they are refreshed in background (without blocking requests) when they are older than the refresh interval.
+/// When a JWT has an unknown key ID (for example when Keycloak rotated the keys of the realm), keys are fetched again before giving up, at most once per minimal refetch interval.
+/// Requests that need keys while they are being fetched wait for the running fetch instead of failing.
+///
+/// When a fetch fails, the previous keys are kept.
+/// A JWT whose key cannot be found while keys were never fetched (or the last fetch failed) is rejected with a 503 error ([KeySetUnavailable](super::AuthError::KeySetUnavailable)), rather than as a JWT signed by an unknown key.
+/// Cloning a `RemoteKeySet` is cheap: clones share the same keys.
+#[derive(Clone)]
+pub struct RemoteKeySet {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    fetcher: Box<dyn JwksFetcher>,
+    config: RemoteKeySetConfig,
+    keys: RwLock<Option<FetchedKeys>>,
+    last_fetch_attempt: Mutex<Option<Instant>>,
+    /// Why the last fetch failed (if it did)
+    last_fetch_error: Mutex<Option<String>>,
+    refreshing: AtomicBool,
+    fetch_done: Notify,
+}
+
+struct FetchedKeys {
+    key_set: KeySet,
+    fetched_at: Instant,
+}
+
+impl RemoteKeySet {
+    /// Create a remote key set that uses the provided fetcher and the default settings
+    pub fn new<F: JwksFetcher + 'static>(fetcher: F) -> Self {
+        Self::with_config(fetcher, RemoteKeySetConfig::default())
+    }
+
+    /// Create a remote key set that uses the provided fetcher and settings
+    pub fn with_config<F: JwksFetcher + 'static>(fetcher: F, config: RemoteKeySetConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                fetcher: Box::new(fetcher),
+                config,
+                keys: RwLock::new(None),
+                last_fetch_attempt: Mutex::new(None),
+                last_fetch_error: Mutex::new(None),
+                refreshing: AtomicBool::new(false),
+                fetch_done: Notify::new(),
+            }),
+        }
+    }
+
+    /// Create a remote key set that fetches the JWKS document from the provided URL, with the default settings
+    #[cfg(feature = "http_client")]
+    pub fn from_url<U: Into<String>>(url: U) -> Self {
+        Self::new(HttpJwksFetcher::new(url))
+    }
+
+    /// Get the keys that were fetched (if any)
+    pub fn key_set(&self) -> Option<KeySet> {
+        self.read_keys().as_ref().map(|k| k.key_set.clone())
+    }
+
+    /// Fetch the JWKS document immediately, regardless of the refresh settings (after the running fetch, if any)
+    pub async fn refresh(&self) -> Result<(), KeyError> {
+        let guard = loop {
+            if let Some(guard) = self.claim_fetch() {
+                break guard;
+            }
+            self.wait_for_fetch().await;
+        };
+        *self.lock_last_fetch_attempt() = Some(Instant::now());
+        self.fetch(guard).await
+    }
+
+    /// Find the key that must be used to verify a JWT, given the key ID from its header
+    ///
+    /// When no key is found, fails with the reason why keys are unavailable (if they were never fetched or the last fetch failed).
+    pub(crate) async fn find(&self, kid: Option<&str>) -> Result<Option<DecodingKey>, String> {
+        let (key, is_stale) = match self.read_keys().as_ref() {
+            Some(keys) => (
+                keys.key_set.find(kid).cloned(),
+                keys.fetched_at.elapsed() >= self.inner.config.refresh_interval,
+            ),
+            None => (None, true),
+        };
+
+        match key {
+            Some(key) => {
+                if let Some(guard) = is_stale.then(|| self.try_start_fetch()).flatten() {
+                    debug!("Refreshing JWKS in background");
+                    let key_set = self.clone();
+                    actix_web::rt::spawn(async move {
+                        let _ = key_set.fetch(guard).await;
+                    });
+                }
+                Ok(Some(key))
+            }
+            None => {
+                if let Some(guard) = self.try_start_fetch() {
+                    debug!("No key found for kid {:?}, fetching JWKS", kid);
+                    let _ = self.fetch(guard).await;
+                } else if self.wait_for_fetch().await {
+                    debug!(
+                        "No key found for kid {:?}, waited for the running JWKS fetch",
+                        kid
+                    );
+                } else {
+                    debug!("No key found for kid {:?} (JWKS was fetched recently)", kid);
+                }
+
+                let keys = self.read_keys();
+                let key = keys
+                    .as_ref()
+                    .and_then(|keys| keys.key_set.find(kid).cloned());
+                let last_fetch_error = self.lock_last_fetch_error().clone();
+                match (key, keys.is_some(), last_fetch_error) {
+                    (Some(key), _, _) => Ok(Some(key)),
+                    (None, _, Some(e)) => Err(e),
+                    (None, false, None) => Err("JWKS was not fetched yet".to_owned()),
+                    (None, true, None) => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Mark a fetch as running, unless one is already running
+    ///
+    /// The fetch ends when the returned guard is dropped (even if it is never passed to [fetch](RemoteKeySet::fetch)).
+    fn claim_fetch(&self) -> Option<FetchGuard> {
+        self.inner
+            .refreshing
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| FetchGuard(self.inner.clone()))
+    }
+
+    /// Check that fetching is allowed (a fetch is not running and the last one is old enough), and register a new fetch attempt
+    ///
+    /// When it returns a guard, the caller owns the running fetch and should pass it to [fetch](RemoteKeySet::fetch).
+    fn try_start_fetch(&self) -> Option<FetchGuard> {
+        let guard = self.claim_fetch()?;
+
+        let mut last_fetch_attempt = self.lock_last_fetch_attempt();
+        let allowed = match *last_fetch_attempt {
+            Some(instant) => instant.elapsed() >= self.inner.config.min_refetch_interval,
+            None => true,
+        };
+        if !allowed {
+            return None;
+        }
+        *last_fetch_attempt = Some(Instant::now());
+        Some(guard)
+    }
+
+    /// Wait for the running fetch to end (if any), returning whether there was one
+    async fn wait_for_fetch(&self) -> bool {
+        let notified = self.inner.fetch_done.notified();
+        futures_util::pin_mut!(notified);
+        // Register as a waiter before checking the flag, so that the end of the fetch cannot be missed
+        notified.as_mut().enable();
+        if !self.inner.refreshing.load(Ordering::SeqCst) {
+            return false;
+        }
+        notified.await;
+        true
+    }
+
+    /// Fetch the JWKS document, as the owner of the running fetch
+    ///
+    /// The fetch ends (and waiters are woken up) when the guard is dropped, even if this future is dropped before it completes (or without being polled).
+    async fn fetch(&self, _guard: FetchGuard) -> Result<(), KeyError> {
+        let result = self
+            .inner
+            .fetcher
+            .fetch()
+            .await
+            .and_then(|jwks| KeySet::from_jwks(&jwks));
+
+        *self.lock_last_fetch_error() = result.as_ref().err().map(|e| e.to_string());
+        match result {
+            Ok(key_set) => {
+                debug!("JWKS was fetched");
+                *self.inner.keys.write().unwrap_or_else(|e| e.into_inner()) = Some(FetchedKeys {
+                    key_set,
+                    fetched_at: Instant::now(),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Failed to fetch JWKS, keeping previous keys ({})", &e);
+                Err(e)
+            }
+        }
+    }
+
+    fn read_keys(&self) -> std::sync::RwLockReadGuard<'_, Option<FetchedKeys>> {
+        self.inner.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_last_fetch_error(&self) -> std::sync::MutexGuard<'_, Option<String>> {
+        self.inner
+            .last_fetch_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_last_fetch_attempt(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.inner
+            .last_fetch_attempt
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks the end of a JWKS fetch when it is dropped
+struct FetchGuard(Arc<Inner>);
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        self.0.refreshing.store(false, Ordering::SeqCst);
+        self.0.fetch_done.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JWKS: &str = r#"{
+        "keys": [
+            {
+                "kid": "sig-key",
+                "kty": "RSA",
+                "alg": "RS256",
+                "use": "sig",
+                "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw",
+                "e": "AQAB"
+            }
+        ]
+    }"#;
+
+    #[actix_web::test]
+    async fn unavailable_keys() {
+        let is_available = Arc::new(AtomicBool::new(false));
+        let key_set = RemoteKeySet::with_config(
+            {
+                let is_available = is_available.clone();
+                move || {
+                    let result = if is_available.load(Ordering::SeqCst) {
+                        Ok(JWKS.to_owned())
+                    } else {
+                        Err(KeyError::Fetch("connection refused".to_owned()))
+                    };
+                    async move { result }
+                }
+            },
+            RemoteKeySetConfig {
+                refresh_interval: Duration::from_secs(3600),
+                min_refetch_interval: Duration::ZERO,
+            },
+        );
+
+        // Keys were never fetched
+        assert_eq!(
+            key_set.find(Some("sig-key")).await.err(),
+            Some("Cannot fetch JWKS (connection refused)".to_owned())
+        );
+
+        is_available.store(true, Ordering::SeqCst);
+        assert!(matches!(key_set.find(Some("sig-key")).await, Ok(Some(_))));
+        assert!(matches!(key_set.find(Some("other-key")).await, Ok(None)));
+
+        // The last fetch failed: known keys can still be used
+        is_available.store(false, Ordering::SeqCst);
+        assert_eq!(
+            key_set.find(Some("other-key")).await.err(),
+            Some("Cannot fetch JWKS (connection refused)".to_owned())
+        );
+        assert!(matches!(key_set.find(Some("sig-key")).await, Ok(Some(_))));
+    }
+
+    #[test]
+    fn background_fetch_that_never_runs() {
+        let key_set = RemoteKeySet::with_config(
+            || async { Ok::<_, KeyError>(JWKS.to_owned()) },
+            RemoteKeySetConfig {
+                refresh_interval: Duration::ZERO,
+                min_refetch_interval: Duration::ZERO,
+            },
+        );
+        actix_web::rt::System::new()
+            .block_on(key_set.refresh())
+            .unwrap();
+
+        // Keys are stale: a background fetch is spawned, but the runtime stops before it runs
+        let key = actix_web::rt::System::new().block_on(key_set.find(Some("sig-key")));
+        assert!(matches!(key, Ok(Some(_))));
+
+        assert!(!key_set.inner.refreshing.load(Ordering::SeqCst));
+        assert!(actix_web::rt::System::new()
+            .block_on(key_set.refresh())
+            .is_ok());
+    }
+}
@@ -0,0 +1,270 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use jsonwebtoken::jwk::{Jwk, PublicKeyUse};
+use jsonwebtoken::DecodingKey;
+use log::debug;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+use super::errors::AuthError;
+use super::jwks::RemoteKeySet;
+
+/// Where the middleware gets the keys used to verify JWT
+#[derive(Clone)]
+pub enum KeySource {
+    /// A fixed set of keys
+    Static(KeySet),
+    /// A set of keys that is fetched from a JWKS endpoint and kept up to date
+    Remote(RemoteKeySet),
+}
+
+impl KeySource {
+    /// Find the key that must be used to verify a JWT, given the key ID from its header (fails if the keys cannot be loaded)
+    pub(crate) async fn find(&self, kid: Option<&str>) -> Result<Option<DecodingKey>, AuthError> {
+        match self {
+            Self::Static(key_set) => Ok(key_set.find(kid).cloned()),
+            Self::Remote(remote_key_set) => remote_key_set
+                .find(kid)
+                .await
+                .map_err(AuthError::KeySetUnavailable),
+        }
+    }
+}
+
+impl From<KeySet> for KeySource {
+    fn from(key_set: KeySet) -> Self {
+        Self::Static(key_set)
+    }
+}
+
+impl From<DecodingKey> for KeySource {
+    fn from(key: DecodingKey) -> Self {
+        Self::Static(key.into())
+    }
+}
+
+impl From<RemoteKeySet> for KeySource {
+    fn from(remote_key_set: RemoteKeySet) -> Self {
+        Self::Remote(remote_key_set)
+    }
+}
+
+/// A set of public keys that can be used to verify JWT
+///
+/// Keycloak serves the keys of a realm as a JWKS document at `/realms/{realm}/protocol/openid-connect/certs`.
+/// When verifying a JWT, the key is selected using the key ID (`kid`) from the JWT header.
+///
+/// A key set can also be created from a single [DecodingKey](DecodingKey) (which has no key ID):
+/// this key will then be used to verify every JWT.
+#[derive(Clone, Default)]
+pub struct KeySet {
+    keys: Vec<Key>,
+}
+
+#[derive(Clone)]
+struct Key {
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawJwkSet {
+    keys: Vec<Value>,
+}
+
+impl KeySet {
+    /// Create a key set from a JWKS document
+    ///
+    /// Keys that cannot be used to verify signatures (encryption keys, unsupported key types or algorithms) are skipped.
+    pub fn from_jwks(jwks: &str) -> Result<Self, KeyError> {
+        let raw_jwk_set = serde_json::from_str::<RawJwkSet>(jwks).map_err(KeyError::Parse)?;
+
+        let keys = raw_jwk_set
+            .keys
+            .into_iter()
+            .filter_map(|raw_jwk| match serde_json::from_value::<Jwk>(raw_jwk) {
+                Ok(jwk) => Key::from_jwk(&jwk),
+                Err(e) => {
+                    debug!("Skipping JWK that cannot be parsed ({})", e);
+                    None
+                }
+            })
+            .collect::<Vec<Key>>();
+
+        if keys.is_empty() {
+            Err(KeyError::NoUsableKey)
+        } else {
+            debug!("Loaded {} key(s) from JWKS", keys.len());
+            Ok(Self { keys })
+        }
+    }
+
+    /// Create a key set from a file containing a JWKS document
+    pub fn from_jwks_file<P: AsRef<Path>>(path: P) -> Result<Self, KeyError> {
+        let jwks = std::fs::read_to_string(path).map_err(KeyError::Io)?;
+        Self::from_jwks(&jwks)
+    }
+
+    /// Add a key to the set
+    pub fn with_key(mut self, kid: Option<String>, key: DecodingKey) -> Self {
+        self.keys.push(Key { kid, key });
+        self
+    }
+
+    /// Key IDs of the keys in the set (`None` for keys without key ID)
+    pub fn kids(&self) -> Vec<Option<&str>> {
+        self.keys.iter().map(|k| k.kid.as_deref()).collect()
+    }
+
+    /// Number of keys in the set
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns `true` if the set contains no key
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Find the key that must be used to verify a JWT, given the key ID from its header
+    ///
+    /// A key without key ID matches any JWT, but a key with the exact key ID is always preferred.
+    /// When the JWT has no key ID, the set must either contain a key without key ID or a single key.
+    pub fn find(&self, kid: Option<&str>) -> Option<&DecodingKey> {
+        let exact_match =
+            || kid.and_then(|kid| self.keys.iter().find(|k| k.kid.as_deref() == Some(kid)));
+        let without_kid = || self.keys.iter().find(|k| k.kid.is_none());
+        let single = || match (kid, self.keys.as_slice()) {
+            (None, [single]) => Some(single),
+            _ => None,
+        };
+
+        exact_match()
+            .or_else(without_kid)
+            .or_else(single)
+            .map(|k| &k.key)
+    }
+}
+
+impl Key {
+    fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let kid = jwk.common.key_id.to_owned();
+
+        if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+            debug!("Skipping JWK {:?} because it is an encryption key", &kid);
+            return None;
+        }
+
+        match DecodingKey::from_jwk(jwk) {
+            Ok(key) => Some(Self { kid, key }),
+            Err(e) => {
+                debug!("Skipping JWK {:?} because it is invalid ({})", &kid, e);
+                None
+            }
+        }
+    }
+}
+
+impl From<DecodingKey> for KeySet {
+    fn from(key: DecodingKey) -> Self {
+        Self::default().with_key(None, key)
+    }
+}
+
+/// An error that happened while loading keys
+#[derive(Debug)]
+pub enum KeyError {
+    /// The JWKS document cannot be read
+    Io(std::io::Error),
+    /// The JWKS document cannot be fetched
+    Fetch(String),
+    /// The JWKS document cannot be parsed
+    Parse(serde_json::Error),
+    /// The JWKS document does not contain any key that can be used to verify JWT
+    NoUsableKey,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Cannot read JWKS ({})", e),
+            Self::Fetch(e) => write!(f, "Cannot fetch JWKS ({})", e),
+            Self::Parse(e) => write!(f, "Cannot parse JWKS ({})", e),
+            Self::NoUsableKey => f.write_str("JWKS does not contain any usable key"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JWKS: &str = r#"{
+        "keys": [
+            {
+                "kid": "sig-key",
+                "kty": "RSA",
+                "alg": "RS256",
+                "use": "sig",
+                "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw",
+                "e": "AQAB"
+            },
+            {
+                "kid": "enc-key",
+                "kty": "RSA",
+                "alg": "RSA-OAEP",
+                "use": "enc",
+                "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw",
+                "e": "AQAB"
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn skip_encryption_keys() {
+        let key_set = KeySet::from_jwks(JWKS).unwrap();
+        assert_eq!(key_set.kids(), vec![Some("sig-key")]);
+    }
+
+    #[test]
+    fn find_by_kid() {
+        let key_set = KeySet::from_jwks(JWKS).unwrap();
+        assert!(key_set.find(Some("sig-key")).is_some());
+        assert!(key_set.find(Some("enc-key")).is_none());
+        assert!(key_set.find(Some("other-key")).is_none());
+    }
+
+    #[test]
+    fn find_single_key_without_kid() {
+        let key_set = KeySet::from_jwks(JWKS).unwrap();
+        assert!(key_set.find(None).is_some());
+
+        let key_set = key_set.with_key(Some("other-key".to_owned()), DecodingKey::from_secret(b""));
+        assert!(key_set.find(None).is_none());
+    }
+
+    #[test]
+    fn key_without_kid_matches_any_kid() {
+        let key_set = KeySet::from(DecodingKey::from_secret(b""));
+        assert!(key_set.find(Some("any-key")).is_some());
+        assert!(key_set.find(None).is_some());
+    }
+
+    #[test]
+    fn no_usable_key() {
+        let result = KeySet::from_jwks(r#"{ "keys": [{ "kty": "unknown" }] }"#);
+        assert!(matches!(result, Err(KeyError::NoUsableKey)));
+    }
+
+    #[test]
+    fn invalid_jwks() {
+        let result = KeySet::from_jwks(r#"{ "not_keys": [] }"#);
+        assert!(matches!(result, Err(KeyError::Parse(_))));
+    }
+}
@@ -32,6 +32,196 @@
 //! ```
 //!
 //! HTTP requests to `GET /private` will need to have a `Authorization` header containing `Bearer [JWT]` where `[JWT]` is a valid JWT that was signed by the private key associated with the public key provided when the middleware was initialized.
+//! The scheme is case-insensitive (`bearer [JWT]` is accepted too), but other schemes (like `Basic`) and malformed values are rejected.
+//!
+//! ## Extract JWT from elsewhere
+//!
+//! By default, JWT are extracted from the `Authorization` header.
+//! Other places can be searched, in order, using [token sources](TokenSource): headers (containing `Bearer [JWT]`), cookies, query parameters, or a custom extractor.
+//! A header that does not contain a bearer token (for example `Authorization: Basic ...`, added by a proxy) does not prevent the next sources from being tried: its error is only returned if no other source contains a JWT.
+//! When no JWT is found, the [NoAuthorizationHeader](AuthError::NoAuthorizationHeader) error tells which sources were tried.
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth, TokenSource};
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     token_sources: vec![
+//!         TokenSource::authorization_header(),
+//!         TokenSource::Cookie("access_token".to_owned()), // For server-rendered pages
+//!         TokenSource::Query("access_token".to_owned()), // For WebSocket upgrades
+//!     ],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! ## Use the keys of a realm (JWKS)
+//!
+//! Keycloak serves the public keys of a realm as a JWKS document (at `/realms/{realm}/protocol/openid-connect/certs`).
+//! Instead of a single public key, the middleware can be given a [KeySet](KeySet) loaded from such a document: the key used to verify a JWT is then selected using the key ID (`kid`) from its header.
+//! This way, several keys can be valid at the same time (for example while Keycloak rotates the keys of a realm).
+//!
+//! ```
+//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, KeySet};
+//!
+//! # const JWKS: &str = r#"{"keys":[{"kid":"GnPPHk3aTkRYwWzS9Z8alrW6wwYDYUMYd1c84xACL8g","kty":"RSA","alg":"RS256","use":"sig","n":"nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw","e":"AQAB"}]}"#;
+//! // const JWKS: &str = "..."; // You should get this from configuration (or use KeySet::from_jwks_file)
+//!
+//! let keycloak_auth = KeycloakAuth::default_with_keys(KeySet::from_jwks(JWKS).unwrap());
+//! ```
+//!
+//! Keys can also be fetched from Keycloak, and kept up to date, using a [RemoteKeySet](RemoteKeySet).
+//! Keys are refreshed in background once they get old, and fetched again when a JWT has an unknown key ID (so that key rotations are picked up without restarting the application).
+//! The HTTP transport can be replaced by implementing the [JwksFetcher](JwksFetcher) trait (or by using a closure); the default one requires the `http_client` feature (not enabled by default).
+//!
+//! ```
+//! # #[cfg(feature = "http_client")]
+//! # {
+//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, RemoteKeySet};
+//!
+//! let keycloak_auth = KeycloakAuth::default_with_keys(RemoteKeySet::from_url(
+//!     "https://sso.example.com/realms/my-realm/protocol/openid-connect/certs",
+//! ));
+//! # }
+//! ```
+//!
+//! ## Check expiration and age
+//!
+//! JWT are rejected once they expired (`exp` claim) and, by default, while they are not valid yet (`nbf` claim).
+//! A leeway (60 seconds by default) is applied to these checks to tolerate clock skew between Keycloak and the server.
+//! It is also possible to reject JWT that were issued (`iat` claim) too long ago, even when they are not expired.
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth};
+//! use std::time::Duration;
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     leeway: Duration::from_secs(5),
+//!     max_age: Some(Duration::from_secs(3600)),
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! The current time is given by a [Clock](Clock) (the clock of the system by default).
+//! Using a [FixedClock](FixedClock) makes it possible to test how JWT are handled around their expiration date, without waiting.
+//!
+//! ## Check issuer and audience
+//!
+//! JWT can be required to be emitted by some issuers (`iss` claim, usually the URL of the realm) and to be intended for some audiences (`aud` claim, usually a client ID).
+//! JWT that do not match are rejected with a 401 error.
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth};
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     issuers: vec!["https://sso.example.com/realms/my-realm".to_owned()],
+//!     audiences: vec!["my-client".to_owned()], // The "aud" claim can be a string or an array
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! ## Restrict signing algorithms
+//!
+//! By default, a JWT can be signed with any algorithm of the family of the key that verifies it (for example `RS256` or `PS512` for a RSA key): a JWT that claims to use another algorithm (like `HS256`) is rejected.
+//! It is possible to only allow some algorithms:
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web_middleware_keycloak_auth::{Algorithm, DecodingKey, KeycloakAuth};
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     allowed_algorithms: vec![Algorithm::RS256],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! ## Configure from a realm URL (OpenID Connect discovery)
+//!
+//! The middleware can also be configured using only the URL of a realm: the `.well-known/openid-configuration` document of the realm gives the URL of its keys (that are then used as a [RemoteKeySet](RemoteKeySet)), its issuer (JWT emitted by another issuer are rejected) and its supported signing algorithms (that become the allowed algorithms).
+//! [discover](KeycloakAuth::discover) requires the `http_client` feature; otherwise, the HTTP client can be given by using [discover_with_client](KeycloakAuth::discover_with_client) with an implementation of the [HttpClient](HttpClient) trait.
+//!
+//! ```no_run
+//! use actix_web_middleware_keycloak_auth::KeycloakAuth;
+//!
+//! # #[cfg(feature = "http_client")]
+//! # async fn setup() {
+//! let keycloak_auth = KeycloakAuth::discover("https://sso.example.com/realms/my-realm")
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! ## Introspect tokens (RFC 7662)
+//!
+//! JWT are verified offline, so a token that was revoked (for example by a logout) remains valid until it expires.
+//! Instead, tokens can be sent to the introspection endpoint of the realm (using the credentials of a confidential client): tokens that are not active are rejected, and the introspection response is used as claims (issuer, audience, roles, ... are still checked).
+//! When the introspection endpoint cannot be reached (or gives an invalid response), requests are rejected with a 503 error.
+//! [Introspection::new](Introspection::new) requires the `http_client` feature; otherwise, the HTTP client can be given by using [Introspection::with_client](Introspection::with_client) with an implementation of the [HttpClient](HttpClient) trait (that supports POST requests).
+//!
+//! ```
+//! # #[cfg(feature = "http_client")]
+//! # {
+//! use actix_web_middleware_keycloak_auth::{Introspection, KeycloakAuth};
+//!
+//! let keycloak_auth = KeycloakAuth::default_with_introspection(Introspection::new(
+//!     &Introspection::keycloak_endpoint("https://sso.example.com/realms/my-realm"),
+//!     "my-api",
+//!     "my-client-secret",
+//! ));
+//! # }
+//! ```
+//!
+//! ## Cache validation results
+//!
+//! When tokens are introspected (or verified with expensive settings), a [ValidationCache](ValidationCache) avoids validating the same token on every request: the claims of valid tokens are kept until they expire (`exp` claim) at most.
+//! [InMemoryValidationCache](InMemoryValidationCache) is a bounded in-memory implementation with hit and miss counters; the trait can be implemented to use a store shared between servers.
+//!
+//! **A cache must not be shared between middlewares that validate tokens differently** (different keys, issuers, introspection endpoints, ...): entries are keyed by token only, so a token that was validated by one of them would be accepted by the others without being verified.
+//! When tokens are introspected, a cached result also hides their revocation by Keycloak (for example by a logout) until the entry expires: use a short maximal duration.
+//!
+//! ```
+//! # #[cfg(feature = "http_client")]
+//! # {
+//! use actix_web_middleware_keycloak_auth::{InMemoryValidationCache, Introspection, KeycloakAuth};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     validation_cache: Some(Arc::new(InMemoryValidationCache::new(10_000, Duration::from_secs(30)))),
+//!     ..KeycloakAuth::default_with_introspection(Introspection::new(
+//!         &Introspection::keycloak_endpoint("https://sso.example.com/realms/my-realm"),
+//!         "my-api",
+//!         "my-client-secret",
+//!     ))
+//! };
+//! # }
+//! ```
+//!
+//! ## Reject revoked tokens
+//!
+//! A [RevocationChecker](RevocationChecker) can reject tokens immediately (for example when a user is disabled or a token leaked), before they expire: it is called after the token is validated, and revoked tokens get a 401 error.
+//! [RevocationList](RevocationList) is an in-memory deny list of tokens (`jti` claim), sessions (`sid` claim) and subjects (`sub` claim) that can be updated while the middleware is running.
+//!
+//! ```
+//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RevocationList};
+//! use chrono::{Duration, Utc};
+//! use std::sync::Arc;
+//!
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let revocation_list = RevocationList::default();
+//! let keycloak_auth = KeycloakAuth {
+//!     revocation_checker: Some(Arc::new(revocation_list.clone())),
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//!
+//! // The token is revoked until it expires (plus the leeway of the middleware)
+//! revocation_list.revoke_token(
+//!     "5a7fd2cb-0ba6-4b09-8bd1-6f6d8ba3b13e",
+//!     Utc::now() + Duration::minutes(6),
+//! );
+//! ```
 //!
 //! ## Require roles
 //!
@@ -39,12 +229,9 @@
 //! If they are not provided, the middleware will return a 403 error.
 //!
 //! ```
-//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, Role, AlwaysReturnPolicy};
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, Role};
 //! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
 //! let keycloak_auth = KeycloakAuth {
-//!     detailed_responses: true,
-//!     passthrough_policy: AlwaysReturnPolicy,
-//!     keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
 //!     required_roles: vec![
 //!         Role::Realm { role: "admin".to_owned() }, // The "admin" realm role must be provided in the JWT
 //!         Role::Client {
@@ -52,6 +239,67 @@
 //!             role: "readonly".to_owned()
 //!         }, // The "readonly" role of the "backoffice" client must be provided in the JWT
 //!     ],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! Roles can also be combined using a [RoleExpr](RoleExpr) (all of, any of, not), set as `role_requirement`.
+//! When it is not satisfied, the [MissingRoles](AuthError::MissingRoles) error contains the part of the expression that failed.
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RoleExpr};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     // The "admin" realm role or the "readonly" role of the "backoffice" client must be provided in the JWT, but not the "banned" realm role
+//!     role_requirement: Some(RoleExpr::all(vec![
+//!         RoleExpr::any(vec![
+//!             RoleExpr::realm("admin"),
+//!             RoleExpr::client("backoffice", "readonly"),
+//!         ]),
+//!         RoleExpr::not(RoleExpr::realm("banned")),
+//!     ])),
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! Roles and role expressions can also be parsed from strings (for example, to load them from configuration), and deserialized from strings using Serde.
+//! Client roles are written `client.role`; dots in client IDs must be escaped with a backslash (`my\.app.role`).
+//!
+//! ```
+//! use actix_web_middleware_keycloak_auth::{Role, RoleExpr};
+//!
+//! let role: Role = r"my\.app.readonly".parse().unwrap();
+//! assert_eq!(role, Role::Client { client: "my.app".to_owned(), role: "readonly".to_owned() });
+//!
+//! let expr: RoleExpr = "all(any(admin, backoffice.readonly), not(banned))".parse().unwrap();
+//! assert_eq!(expr.to_string(), "all(any(admin, backoffice.readonly), not(banned))");
+//! ```
+//!
+//! By default, roles are extracted from the `realm_access` and `resource_access` claims, like Keycloak does.
+//! If custom protocol mappers put them somewhere else, the places where they are searched can be changed using `role_sources` (see [RoleSource](RoleSource)).
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RoleSource};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     role_sources: vec![
+//!         RoleSource::Realm("/roles".to_owned()), // Realm roles are in a "roles" claim
+//!         RoleSource::Realm("/https:~1~1example.com~1roles".to_owned()), // And in a "https://example.com/roles" claim
+//!     ],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! Roles that imply other roles (like composite roles whose children are not included in JWT) can be expanded using a [RoleMapping](RoleMapping), set as `role_mapping`.
+//! Implied roles are added before roles are checked, and are provided to handlers.
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RoleMapping};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     // The "admin" realm role implies the "readonly" role of the "backoffice" client
+//!     role_mapping: Some(RoleMapping::from_json(r#"{"admin": ["backoffice.readonly"]}"#).unwrap()),
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
 //! };
 //! ```
 //!
@@ -69,13 +317,129 @@
 //! }
 //! ```
 //!
+//! ## Require groups
+//!
+//! If the group membership mapper of Keycloak adds a `groups` claim to JWT, you can require the user to be a member of some groups (identified by their path).
+//! A member of a subgroup is also considered a member of its parent groups: `/org` matches users of `/org/team-a`.
+//! If they are not members, the middleware will return a 403 error.
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     required_groups: vec!["/org".to_owned()], // The user must be a member of "/org" or of one of its subgroups
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! There is also a [KeycloakGroups](KeycloakGroups) extractor that can be used to get the groups of the user from handlers.
+//!
+//! ```
+//! use actix_web::{HttpResponse, Responder};
+//! use actix_web_middleware_keycloak_auth::KeycloakGroups;
+//!
+//! async fn private(groups: KeycloakGroups) -> impl Responder {
+//!     if groups.is_member_of("/org/team-a") {
+//!         HttpResponse::Ok().body("Hello team A!")
+//!     } else {
+//!         HttpResponse::Ok().body("Hello!")
+//!     }
+//! }
+//! ```
+//!
+//! ## Require OAuth scopes
+//!
+//! You can require some OAuth scopes to be granted in JWT (in the space-separated `scope` claim).
+//! If they are not granted, the middleware will return a 403 error with an `insufficient_scope` error code (and the required scopes) in the `WWW-Authenticate` header.
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     required_scopes: vec!["orders:read".to_owned()],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! The granted scopes are available from handlers using the [KeycloakScopes](KeycloakScopes) extractor.
+//!
+//! ```
+//! use actix_web::{HttpResponse, Responder};
+//! use actix_web_middleware_keycloak_auth::KeycloakScopes;
+//!
+//! async fn orders(scopes: KeycloakScopes) -> impl Responder {
+//!     if scopes.has("orders:write") {
+//!         HttpResponse::Ok().body("Orders (editable)")
+//!     } else {
+//!         HttpResponse::Ok().body("Orders")
+//!     }
+//! }
+//! ```
+//!
+//! ## Require permissions (Keycloak Authorization Services)
+//!
+//! When Keycloak Authorization Services are used, RPT (requesting party tokens) contain the permissions granted to the user (in the `authorization.permissions` claim): resources, and scopes on these resources.
+//! You can require some permissions to be granted; resources are identified by their name or ID, and a permission without scope only requires some access to the resource.
+//!
+//! ```
+//! # use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, PermissionRequirement};
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     required_permissions: vec![PermissionRequirement::resource_scope("orders", "read")],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! Permissions can also be required per route, resource or scope using the [PermissionCheck](PermissionCheck) middleware (like [RoleCheck](RoleCheck)), and are available from handlers using the [KeycloakPermissions](KeycloakPermissions) extractor.
+//!
+//! ```
+//! use actix_web::{HttpResponse, Responder};
+//! use actix_web_middleware_keycloak_auth::KeycloakPermissions;
+//!
+//! async fn orders(permissions: KeycloakPermissions) -> impl Responder {
+//!     if permissions.has("orders", Some("write")) {
+//!         HttpResponse::Ok().body("Orders (editable)")
+//!     } else {
+//!         HttpResponse::Ok().body("Orders")
+//!     }
+//! }
+//! ```
+//!
+//! ## Custom claim validators
+//!
+//! Checks that depend on claims and on the request (for example, a tenant that must match the `Host` header) can be added as [ClaimValidator](ClaimValidator)s.
+//! They are run in order, after the other requirements are checked, and can be asynchronous.
+//! Their errors are rendered like the other errors of the middleware: [AuthError::custom](AuthError::custom) creates an error with a custom code (used in `application/problem+json` responses) and message (a 403 error).
+//!
+//! ```
+//! use actix_web::dev::ServiceRequest;
+//! use actix_web_middleware_keycloak_auth::{AuthError, KeycloakAuth, DecodingKey};
+//! use serde_json::Value;
+//! use std::sync::Arc;
+//!
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth {
+//!     claim_validators: vec![Arc::new(|claims: &Value, req: &ServiceRequest| {
+//!         let is_valid = claims.get("tenant_id").and_then(Value::as_str) == Some(req.connection_info().host());
+//!         async move {
+//!             if is_valid {
+//!                 Ok(())
+//!             } else {
+//!                 Err(AuthError::custom("wrong_tenant", "JWT token belongs to another tenant"))
+//!             }
+//!         }
+//!     })],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
 //! ## Use several authentication profiles
 //!
 //! It is possible to setup multiple authentication profiles if, for example, multiple groups of routes require different roles.
 //!
 //! ```
 //! use actix_web::{App, web, HttpResponse};
-//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, Role, AlwaysReturnPolicy};
+//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, Role};
 //!
 //! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
 //! // const KEYCLOAK_PK: &str = "..."; // You should get this from configuration
@@ -85,10 +449,8 @@
 //!
 //! // Admin realm role is required
 //! let keycloak_auth_admin = KeycloakAuth {
-//!     detailed_responses: true,
-//!     passthrough_policy: AlwaysReturnPolicy,
-//!     keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
 //!     required_roles: vec![Role::Realm { role: "admin".to_owned() }],
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
 //! };
 //!
 //! App::new()
@@ -105,6 +467,70 @@
 //!     .service(web::resource("/").to(|| async { HttpResponse::Ok().body("Hello World") }));
 //! ```
 //!
+//! ## Require roles per route
+//!
+//! Instead of creating one authentication profile per set of roles, a single middleware can authenticate requests while roles are checked per route, resource or scope:
+//!
+//! - the [RoleCheck](RoleCheck) middleware responds with a 403 error when roles are missing, rendered like the errors of the authentication middleware
+//! - the [RoleGuard](RoleGuard) guard only matches requests that have the required roles (so that another route can handle the other ones)
+//!
+//! ```
+//! use actix_web::{App, web, HttpResponse};
+//! use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RoleCheck, RoleExpr, RoleGuard};
+//!
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! let keycloak_auth = KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+//!
+//! App::new().service(
+//!     web::scope("/private")
+//!         .wrap(keycloak_auth) // User must be authenticated
+//!         .service(
+//!             web::resource("/admin")
+//!                 .wrap(RoleCheck::new(RoleExpr::realm("admin"))) // User must have the "admin" role
+//!                 .route(web::get().to(|| async { HttpResponse::Ok().body("Admin") })),
+//!         )
+//!         .service(
+//!             web::resource("/dashboard")
+//!                 .route(
+//!                     web::get()
+//!                         .guard(RoleGuard::new(RoleExpr::realm("admin")))
+//!                         .to(|| async { HttpResponse::Ok().body("Admin dashboard") }),
+//!                 )
+//!                 .route(web::get().to(|| async { HttpResponse::Ok().body("User dashboard") })),
+//!         ),
+//! );
+//! ```
+//!
+//! [RoleGuard](RoleGuard) can also be used with the `guard` argument of route macros, through a function:
+//!
+//! ```
+//! use actix_web::guard::{Guard, GuardContext};
+//! use actix_web::{get, HttpResponse, Responder};
+//! use actix_web_middleware_keycloak_auth::{RoleExpr, RoleGuard};
+//!
+//! fn is_admin(ctx: &GuardContext) -> bool {
+//!     RoleGuard::new(RoleExpr::realm("admin")).check(ctx)
+//! }
+//!
+//! #[get("/dashboard", guard = "is_admin")]
+//! async fn admin_dashboard() -> impl Responder {
+//!     HttpResponse::Ok().body("Admin dashboard")
+//! }
+//! ```
+//!
+//! Roles can also be required from the signature of handlers, using the [RequireRoles](RequireRoles) extractor and a type that describes the required roles (see [RoleRequirement](RoleRequirement) and the [role_requirement](role_requirement) macro):
+//!
+//! ```
+//! use actix_web::{HttpResponse, Responder};
+//! use actix_web_middleware_keycloak_auth::{role_requirement, RequireRoles};
+//!
+//! role_requirement!(AdminRole = "any(admin, backoffice.admin)");
+//!
+//! async fn admin(roles: RequireRoles<AdminRole>) -> impl Responder {
+//!     HttpResponse::Ok().body(format!("Admin with roles {:?}", &*roles))
+//! }
+//! ```
+//!
 //! ## Access claims from handlers
 //!
 //! When authentication is successful, the middleware will store the decoded JWT claims so that they can be accessed from handlers.
@@ -176,6 +602,64 @@
 //! }
 //! ```
 //!
+//! ## Machine-readable error responses
+//!
+//! By default, error responses have a plain text body.
+//! They can be sent as `application/problem+json` (see [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807)) instead:
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth, ResponseFormat};
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     response_format: ResponseFormat::ProblemJson,
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
+//! For example, when roles are missing:
+//!
+//! ```json
+//! {
+//!   "type": "urn:actix-web-middleware-keycloak-auth:error:missing_roles",
+//!   "title": "Forbidden",
+//!   "status": 403,
+//!   "code": "missing_roles",
+//!   "detail": "JWT token is missing roles: admin",
+//!   "missing_roles": ["admin"],
+//!   "correlation_id": "8b4dbc3b-0b6a-4bd3-a8b7-0a9b9c4c1e4e"
+//! }
+//! ```
+//!
+//! The `code` is stable for each [AuthError](AuthError) variant (see [AuthError::code](AuthError::code)).
+//! The correlation ID is taken from the `X-Request-Id` request header (or generated, and logged), so that errors can be matched with logs.
+//! When `detailed_responses` is false, only the `type` (`about:blank`), `title`, `status` and `correlation_id` fields are sent.
+//!
+//! 401 and 403 error responses also have a `WWW-Authenticate` header (see [RFC 6750](https://www.rfc-editor.org/rfc/rfc6750#section-3)), like `Bearer realm="my-realm", error="invalid_token", error_description="JWT token has expired"`.
+//! The realm is set using the `challenge_realm` setting, and the description is only sent when `detailed_responses` is true.
+//! Requests with a malformed `Authorization` header get a 400 error, unless the `unauthorized_on_malformed_header` setting is true.
+//!
+//! For complete control over error responses, an [ErrorRenderer](ErrorRenderer) can be set (a closure that gets the error and the request can be used).
+//! The built-in behaviors are available as [PlainTextErrorRenderer](PlainTextErrorRenderer), [DetailedErrorRenderer](DetailedErrorRenderer), [ProblemJsonErrorRenderer](ProblemJsonErrorRenderer) and [DefaultErrorRenderer](DefaultErrorRenderer).
+//!
+//! ```
+//! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+//! use actix_web::dev::ServiceRequest;
+//! use actix_web::{HttpResponse, ResponseError};
+//! use actix_web_middleware_keycloak_auth::{AuthError, DecodingKey, KeycloakAuth};
+//! use serde_json::json;
+//! use std::sync::Arc;
+//!
+//! let keycloak_auth = KeycloakAuth {
+//!     error_renderer: Some(Arc::new(|e: &AuthError, req: &ServiceRequest| {
+//!         HttpResponse::build(e.status_code()).json(json!({
+//!             "error": { "code": e.code(), "path": req.path() }
+//!         }))
+//!     })),
+//!     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//! };
+//! ```
+//!
 //! ## Make authentication optional
 //!
 //! By default, when the middleware cannot authenticate a request, it immediately responds with a HTTP error (401 or 403 depending on what failed).
@@ -192,17 +676,13 @@
 //! # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
 //! use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, AuthError, PassthroughAction};
 //!
-//! let keycloak_auth_admin = KeycloakAuth {
-//!     detailed_responses: true,
-//!     passthrough_policy: |e: &AuthError| {
+//! let keycloak_auth_admin = KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+//!     .with_passthrough_policy(|e: &AuthError| {
 //!         match e {
-//!             AuthError::NoAuthorizationHeader => PassthroughAction::Pass,
+//!             AuthError::NoAuthorizationHeader(_) => PassthroughAction::Pass,
 //!             _ => PassthroughAction::Return,
 //!         }
-//!     },
-//!     keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
-//!     required_roles: vec![],
-//! };
+//!     });
 //! ```
 //!
 //! When the middleware does not respond immediately (authentication succeeded or the passthrough policy says "pass"), it will always store the authentication status in request-local data.
@@ -225,21 +705,40 @@
 // Force exposed items to be documented
 #![deny(missing_docs)]
 
+mod cache;
+mod clock;
+mod discovery;
 mod errors;
 mod extractors;
+mod groups;
+mod guard;
+mod http;
+mod introspection;
+mod jwks;
+mod keys;
+mod mapping;
+mod permissions;
+mod render;
+mod revocation;
 mod roles;
+mod scopes;
+mod token;
+mod validators;
 
 #[cfg(feature = "paperclip_compat")]
 mod paperclip;
 
+/// _(Re-exported from the `jsonwebtoken` crate)_
+pub use jsonwebtoken::Algorithm;
 /// _(Re-exported from the `jsonwebtoken` crate)_
 pub use jsonwebtoken::DecodingKey;
 
 use actix_web::body::EitherBody;
 use actix_web::dev::{self, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::{Error, HttpMessage};
-use chrono::{serde::ts_seconds, DateTime, Utc};
-use futures_util::future::{ok, ready, FutureExt, LocalBoxFuture, Ready};
+use chrono::{serde::ts_seconds, DateTime, TimeZone, Utc};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{decode, decode_header, Validation};
 use log::{debug, trace};
 use serde::de::DeserializeOwned;
@@ -248,25 +747,100 @@ use serde_json::{from_value, Value};
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
-pub use errors::AuthError;
+use cache::token_hash;
+pub use cache::{CacheStats, InMemoryValidationCache, ValidationCache};
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use discovery::{DiscoveryError, ProviderMetadata};
 pub use errors::ClaimError;
+pub use errors::{AuthError, ResponseFormat};
 pub use extractors::{
-    extract_jwt_claims, KeycloakClaims, KeycloakRoles, StandardKeycloakClaims,
-    UnstructuredKeycloakClaims,
+    extract_jwt_claims, KeycloakClaims, KeycloakGroups, KeycloakPermissions, KeycloakRoles,
+    KeycloakScopes, RequireRoles, StandardKeycloakClaims, UnstructuredKeycloakClaims,
 };
-use roles::{check_roles, extract_roles, Roles};
+pub use groups::Group;
+use groups::{check_groups, parse_groups};
+pub use guard::{PermissionCheck, RequirementCheckMiddleware, RoleCheck, RoleGuard};
+#[cfg(feature = "http_client")]
+pub use http::DefaultHttpClient;
+pub use http::HttpClient;
+pub use introspection::Introspection;
+pub use jwks::{HttpJwksFetcher, JwksFetcher, RemoteKeySet, RemoteKeySetConfig};
+pub use keys::{KeyError, KeySet, KeySource};
+pub use mapping::{RoleMapping, RoleMappingError};
+use permissions::{check_permissions, parse_permissions};
+pub use permissions::{Permission, PermissionRequirement};
+use render::RequestErrorRenderer;
+pub use render::{
+    DefaultErrorRenderer, DetailedErrorRenderer, ErrorRenderer, PlainTextErrorRenderer,
+    ProblemJsonErrorRenderer,
+};
+pub use revocation::{RevocationChecker, RevocationList};
+use roles::{check_role_expr, check_roles};
+pub use roles::{ParseRoleError, RoleExpr, RoleExtractor, RoleRequirement, RoleSource};
+use scopes::{check_scopes, parse_scopes};
+pub use token::{TokenExtractor, TokenSource};
+pub use validators::ClaimValidator;
 
 /// Middleware configuration
 #[derive(Clone)]
 pub struct KeycloakAuth<PP: PassthroughPolicy> {
     /// If true, error responses will be more detailed to explain what went wrong
     pub detailed_responses: bool,
-    /// Public key to use to verify JWT
-    pub keycloak_oid_public_key: DecodingKey,
+    /// Format of error responses
+    pub response_format: ResponseFormat,
+    /// Realm that is announced in the `WWW-Authenticate` header of error responses (if any)
+    pub challenge_realm: Option<String>,
+    /// If true, requests with a malformed `Authorization` header get a 401 error instead of a 400 error
+    pub unauthorized_on_malformed_header: bool,
+    /// Custom renderer of error responses (if set, the `detailed_responses`, `response_format`, `challenge_realm` and `unauthorized_on_malformed_header` settings are not used)
+    pub error_renderer: Option<Arc<dyn ErrorRenderer>>,
+    /// Places of requests where JWT are searched, in order (the first one that contains a token is used)
+    pub token_sources: Vec<TokenSource>,
+    /// Keys to use to verify JWT
+    pub keys: KeySource,
+    /// Token introspection settings: if set, tokens are sent to the introspection endpoint instead of being verified using keys (and the introspection response is used as claims)
+    pub introspection: Option<Introspection>,
+    /// Cache of token validation results (if set, a token that was verified or introspected is not validated again until it expires)
+    ///
+    /// It must not be shared with middlewares that validate tokens differently (see [ValidationCache](ValidationCache)).
+    pub validation_cache: Option<Arc<dyn ValidationCache>>,
+    /// Checker of revoked tokens (if set, it is called after the token is validated, on every request)
+    pub revocation_checker: Option<Arc<dyn RevocationChecker>>,
+    /// Algorithms that JWT are allowed to be signed with (if empty, every algorithm of the family of the key is allowed)
+    pub allowed_algorithms: Vec<Algorithm>,
+    /// Accepted issuers (`iss` claim) of JWT (if empty, the issuer is not checked)
+    pub issuers: Vec<String>,
+    /// Accepted audiences (`aud` claim) of JWT: at least one of them must be included in JWT (if empty, the audience is not checked)
+    pub audiences: Vec<String>,
+    /// Tolerated clock skew between Keycloak and the server, applied when checking the time claims (`exp`, `nbf` and `iat`) of JWT
+    pub leeway: Duration,
+    /// If true, JWT that have a "not before" date (`nbf` claim) in the future are rejected
+    pub validate_nbf: bool,
+    /// If set, JWT that were issued (`iat` claim) longer ago than this are rejected, even if they did not expire yet
+    pub max_age: Option<Duration>,
+    /// Clock used to validate the time claims of JWT
+    pub clock: Arc<dyn Clock>,
+    /// Places of JWT claims where roles are searched (all of them are used)
+    pub role_sources: Vec<RoleSource>,
+    /// Roles that imply other roles, added to the roles extracted from JWT before they are checked (and provided to handlers)
+    pub role_mapping: Option<RoleMapping>,
     /// List of Keycloak roles that must be included in JWT
     pub required_roles: Vec<Role>,
+    /// Combination of Keycloak roles that JWT must satisfy, in addition to `required_roles` (if any)
+    pub role_requirement: Option<RoleExpr>,
+    /// Paths of Keycloak groups that JWT must be a member of (directly, or through one of their subgroups), according to the `groups` claim
+    pub required_groups: Vec<String>,
+    /// OAuth scopes that must be included in JWT (`scope` claim)
+    pub required_scopes: Vec<String>,
+    /// Permissions (from Keycloak Authorization Services) that must be granted by JWT, according to the `authorization.permissions` claim of RPT
+    pub required_permissions: Vec<PermissionRequirement>,
+    /// Custom validators of JWT claims, run in order after the other requirements are checked
+    pub claim_validators: Vec<Arc<dyn ClaimValidator>>,
     /// Policy that defines whether or not the middleware should return a HTTP error or continue to the handler (depending on which error occurred)
     pub passthrough_policy: PP,
 }
@@ -274,18 +848,121 @@ pub struct KeycloakAuth<PP: PassthroughPolicy> {
 impl KeycloakAuth<AlwaysReturnPolicy> {
     /// Create a middleware with the provided public key and the default config
     pub fn default_with_pk(keycloak_oid_public_key: DecodingKey) -> Self {
+        Self::default_with_keys(keycloak_oid_public_key)
+    }
+
+    /// Create a middleware that introspects tokens (see [Introspection](Introspection)) with the default config
+    ///
+    /// No keys are needed to verify tokens, because they are not verified offline.
+    pub fn default_with_introspection(introspection: Introspection) -> Self {
+        Self {
+            introspection: Some(introspection),
+            ..Self::default_with_keys(KeySet::default())
+        }
+    }
+
+    /// Create a middleware with the provided keys and the default config
+    pub fn default_with_keys<K: Into<KeySource>>(keys: K) -> Self {
         Self {
             detailed_responses: true,
-            keycloak_oid_public_key,
+            response_format: ResponseFormat::PlainText,
+            challenge_realm: None,
+            unauthorized_on_malformed_header: false,
+            error_renderer: None,
+            token_sources: vec![TokenSource::authorization_header()],
+            keys: keys.into(),
+            introspection: None,
+            validation_cache: None,
+            revocation_checker: None,
+            allowed_algorithms: vec![],
+            issuers: vec![],
+            audiences: vec![],
+            leeway: Duration::from_secs(60),
+            validate_nbf: true,
+            max_age: None,
+            clock: Arc::new(SystemClock),
+            role_sources: RoleSource::defaults(),
+            role_mapping: None,
             required_roles: vec![],
+            role_requirement: None,
+            required_groups: vec![],
+            required_scopes: vec![],
+            required_permissions: vec![],
+            claim_validators: vec![],
             passthrough_policy: AlwaysReturnPolicy,
         }
     }
+
+    /// Create a middleware with the default config, using the OpenID Connect discovery document of the provided issuer (for example `https://sso.example.com/realms/my-realm`)
+    ///
+    /// Keys are fetched from the `jwks_uri` of the provider (see [RemoteKeySet](RemoteKeySet)), the `iss` claim of JWT must match the issuer, and JWT must be signed with one of the signing algorithms supported by the provider.
+    #[cfg(feature = "http_client")]
+    pub async fn discover(issuer_url: &str) -> Result<Self, DiscoveryError> {
+        Self::discover_with_client(issuer_url, DefaultHttpClient::default()).await
+    }
+
+    /// Same as [discover](KeycloakAuth::discover), but using the provided HTTP client (for the discovery document and the JWKS)
+    pub async fn discover_with_client<C: HttpClient + 'static>(
+        issuer_url: &str,
+        client: C,
+    ) -> Result<Self, DiscoveryError> {
+        let client = Arc::new(client);
+        let metadata = ProviderMetadata::discover(issuer_url, client.as_ref()).await?;
+        debug!(
+            "OpenID Connect provider metadata was fetched (issuer: {}, jwks_uri: {})",
+            &metadata.issuer, &metadata.jwks_uri
+        );
+
+        let allowed_algorithms = metadata.signing_algorithms();
+        let keys = RemoteKeySet::new(HttpJwksFetcher::with_client(metadata.jwks_uri, client));
+        Ok(Self {
+            allowed_algorithms,
+            issuers: vec![metadata.issuer],
+            ..Self::default_with_keys(keys)
+        })
+    }
+}
+
+impl<PP: PassthroughPolicy> KeycloakAuth<PP> {
+    /// Replace the passthrough policy of the middleware configuration
+    pub fn with_passthrough_policy<P: PassthroughPolicy>(
+        self,
+        passthrough_policy: P,
+    ) -> KeycloakAuth<P> {
+        KeycloakAuth {
+            detailed_responses: self.detailed_responses,
+            response_format: self.response_format,
+            challenge_realm: self.challenge_realm,
+            unauthorized_on_malformed_header: self.unauthorized_on_malformed_header,
+            error_renderer: self.error_renderer,
+            token_sources: self.token_sources,
+            keys: self.keys,
+            introspection: self.introspection,
+            validation_cache: self.validation_cache,
+            revocation_checker: self.revocation_checker,
+            allowed_algorithms: self.allowed_algorithms,
+            issuers: self.issuers,
+            audiences: self.audiences,
+            leeway: self.leeway,
+            validate_nbf: self.validate_nbf,
+            max_age: self.max_age,
+            clock: self.clock,
+            role_sources: self.role_sources,
+            role_mapping: self.role_mapping,
+            required_roles: self.required_roles,
+            role_requirement: self.role_requirement,
+            required_groups: self.required_groups,
+            required_scopes: self.required_scopes,
+            required_permissions: self.required_permissions,
+            claim_validators: self.claim_validators,
+            passthrough_policy,
+        }
+    }
 }
 
-impl<PP: PassthroughPolicy, S, B> Transform<S, ServiceRequest> for KeycloakAuth<PP>
+impl<PP: PassthroughPolicy + 'static, S, B> Transform<S, ServiceRequest> for KeycloakAuth<PP>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -298,22 +975,18 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         trace!("Initialize KeycloakAuthMiddleware");
         ok(KeycloakAuthMiddleware {
-            service,
-            detailed_responses: self.detailed_responses,
-            keycloak_oid_public_key: self.keycloak_oid_public_key.clone(),
-            required_roles: self.required_roles.clone(),
-            passthrough_policy: self.passthrough_policy.clone(),
+            service: Rc::new(service),
+            config: Rc::new(self.clone()),
+            error_renderer: self.error_renderer(),
         })
     }
 }
 
 /// Internal middleware configuration
 pub struct KeycloakAuthMiddleware<PP: PassthroughPolicy, S> {
-    service: S,
-    detailed_responses: bool,
-    keycloak_oid_public_key: DecodingKey,
-    required_roles: Vec<Role>,
-    passthrough_policy: PP,
+    service: Rc<S>,
+    config: Rc<KeycloakAuth<PP>>,
+    error_renderer: Arc<dyn ErrorRenderer>,
 }
 
 /// Auth result that is injected in request-local data
@@ -435,12 +1108,6 @@ impl Default for StandardClaims {
     }
 }
 
-impl Roles for StandardClaims {
-    fn roles(&self) -> Vec<Role> {
-        extract_roles(&self.realm_access, &self.resource_access)
-    }
-}
-
 /// Access details
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Access {
@@ -449,7 +1116,9 @@ pub struct Access {
 }
 
 /// A realm or client role
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+///
+/// Roles can be parsed from strings (see the [FromStr](std::str::FromStr) implementation), and deserialized from strings as well as from their serialized form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 #[serde(tag = "type")]
 pub enum Role {
     /// A realm role
@@ -469,8 +1138,42 @@ pub enum Role {
 impl std::fmt::Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Realm { role } => write!(f, "{}", role),
-            Self::Client { client, role } => write!(f, "{}.{}", client, role),
+            Self::Realm { role } => write!(f, "{}", roles::escape(role, true)),
+            Self::Client { client, role } => write!(
+                f,
+                "{}.{}",
+                roles::escape(client, true),
+                roles::escape(role, false)
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(tag = "type")]
+        enum TaggedRole {
+            Realm { role: String },
+            Client { client: String, role: String },
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum StringOrTagged {
+            String(String),
+            Tagged(TaggedRole),
+        }
+
+        match StringOrTagged::deserialize(de)? {
+            StringOrTagged::String(string) => string.parse().map_err(::serde::de::Error::custom),
+            StringOrTagged::Tagged(TaggedRole::Realm { role }) => Ok(Self::Realm { role }),
+            StringOrTagged::Tagged(TaggedRole::Client { client, role }) => {
+                Ok(Self::Client { client, role })
+            }
         }
     }
 }
@@ -516,23 +1219,39 @@ impl UnstructuredClaims {
 #[serde(transparent)]
 struct RawClaims(pub Value);
 
+/// What is extracted from a valid JWT and injected in request-local data
+struct Authentication {
+    claims: Value,
+    roles: Vec<Role>,
+    /// Groups, unless the `groups` claim cannot be parsed (and no group is required)
+    groups: Option<Vec<Group>>,
+    /// Scopes, unless the `scope` claim cannot be parsed (and no scope is required)
+    scopes: Option<Vec<String>>,
+    /// Permissions, unless the `authorization` claim cannot be parsed (and no permission is required)
+    permissions: Option<Vec<Permission>>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
-struct RoleClaims {
-    /// Optional realm roles from Keycloak
-    pub realm_access: Option<Access>,
-    /// Optional client roles from Keycloak
-    pub resource_access: Option<HashMap<String, Access>>,
+struct TimeClaims {
+    /// Expiration date (as a UNIX timestamp)
+    pub exp: i64,
+    /// Optional "not before" date (as a UNIX timestamp)
+    pub nbf: Option<i64>,
+    /// Optional issuance date (as a UNIX timestamp)
+    pub iat: Option<i64>,
 }
 
-impl Roles for RoleClaims {
-    fn roles(&self) -> Vec<Role> {
-        extract_roles(&self.realm_access, &self.resource_access)
-    }
+#[derive(Debug, Clone, Deserialize)]
+struct AudienceClaims {
+    /// Optional audience
+    #[serde(default, deserialize_with = "deserialize_optional_string_or_strings")]
+    pub aud: Option<Vec<String>>,
 }
 
-impl<PP: PassthroughPolicy, S, B> Service<ServiceRequest> for KeycloakAuthMiddleware<PP, S>
+impl<PP: PassthroughPolicy + 'static, S, B> Service<ServiceRequest>
+    for KeycloakAuthMiddleware<PP, S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -543,200 +1262,290 @@ where
     dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let auth_header = req.headers().get("Authorization");
-
-        match auth_header {
-            Some(auth_header_value) => {
-                let auth_header_str = auth_header_value.to_str();
-                match auth_header_str {
-                    Ok(raw_token) => {
-                        let token = raw_token.trim_start_matches("Bearer ");
-                        debug!("Bearer token was extracted from request headers");
-
-                        match decode_header(token) {
-                            Ok(jwt_header) => {
-                                debug!("JWT header was decoded");
-                                debug!("JWT is using {:?} algorithm", &jwt_header.alg);
-
-                                match decode::<Value>(
-                                    token,
-                                    &self.keycloak_oid_public_key,
-                                    &Validation::new(jwt_header.alg),
-                                ) {
-                                    Ok(raw_token) => {
-                                        debug!("JWT was decoded");
-
-                                        match from_value::<RoleClaims>(raw_token.claims.clone()) {
-                                            Ok(role_claims) => {
-                                                let roles = role_claims.roles();
-
-                                                match check_roles(&roles, &self.required_roles) {
-                                                    Ok(_) => {
-                                                        debug!("JWT is valid");
-
-                                                        {
-                                                            let mut extensions =
-                                                                req.extensions_mut();
-                                                            extensions.insert(
-                                                                KeycloakAuthStatus::Success,
-                                                            );
-                                                            extensions.insert(RawClaims(
-                                                                raw_token.claims,
-                                                            ));
-                                                            extensions.insert(roles);
-                                                        }
-
-                                                        Box::pin(
-                                                            self.service
-                                                                .call(req)
-                                                                .map(map_body_left),
-                                                        )
-                                                    }
-                                                    Err(e) => {
-                                                        debug!("{}", &e);
-                                                        match self.passthrough_policy.policy(&e) {
-                                                            PassthroughAction::Pass => {
-                                                                {
-                                                                    let mut extensions =
-                                                                        req.extensions_mut();
-                                                                    extensions.insert(
-                                                                        KeycloakAuthStatus::Failure(
-                                                                            e.clone(),
-                                                                        ),
-                                                                    );
-                                                                }
-                                                                Box::pin(
-                                                                    self.service
-                                                                        .call(req)
-                                                                        .map(map_body_left),
-                                                                )
-                                                            }
-                                                            PassthroughAction::Return => {
-                                                                Box::pin(ready(Ok(req
-                                                                    .into_response(e.to_response(
-                                                                        self.detailed_responses,
-                                                                    ))
-                                                                    .map_into_right_body())))
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                let e = AuthError::RoleParsingError(e.to_string());
-                                                debug!("{}", &e);
-                                                match self.passthrough_policy.policy(&e) {
-                                                    PassthroughAction::Pass => {
-                                                        {
-                                                            let mut extensions =
-                                                                req.extensions_mut();
-                                                            extensions.insert(
-                                                                KeycloakAuthStatus::Failure(
-                                                                    e.clone(),
-                                                                ),
-                                                            );
-                                                        }
-                                                        Box::pin(
-                                                            self.service
-                                                                .call(req)
-                                                                .map(map_body_left),
-                                                        )
-                                                    }
-                                                    PassthroughAction::Return => {
-                                                        Box::pin(ready(Ok(req.into_response(
-                                                            e.to_response(self.detailed_responses)
-                                                                .map_into_right_body(),
-                                                        ))))
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let e = AuthError::DecodeError(e.to_string());
-                                        debug!("{}", &e);
-                                        match self.passthrough_policy.policy(&e) {
-                                            PassthroughAction::Pass => {
-                                                {
-                                                    let mut extensions = req.extensions_mut();
-                                                    extensions.insert(KeycloakAuthStatus::Failure(
-                                                        e.clone(),
-                                                    ));
-                                                }
-                                                Box::pin(self.service.call(req).map(map_body_left))
-                                            }
-                                            PassthroughAction::Return => Box::pin(ready(Ok(req
-                                                .into_response(
-                                                    e.to_response(self.detailed_responses)
-                                                        .map_into_right_body(),
-                                                )))),
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let e = AuthError::InvalidJwt(e.to_string());
-                                debug!("{}", &e);
-                                match self.passthrough_policy.policy(&e) {
-                                    PassthroughAction::Pass => {
-                                        {
-                                            let mut extensions = req.extensions_mut();
-                                            extensions
-                                                .insert(KeycloakAuthStatus::Failure(e.clone()));
-                                        }
-                                        Box::pin(self.service.call(req).map(map_body_left))
-                                    }
-                                    PassthroughAction::Return => Box::pin(ready(Ok(req
-                                        .into_response(
-                                            e.to_response(self.detailed_responses)
-                                                .map_into_right_body(),
-                                        )))),
-                                }
-                            }
+        let service = Rc::clone(&self.service);
+        let config = Rc::clone(&self.config);
+        let error_renderer = Arc::clone(&self.error_renderer);
+
+        Box::pin(async move {
+            match config.authenticate(&req).await {
+                Ok(authentication) => {
+                    debug!("JWT is valid");
+
+                    {
+                        let mut extensions = req.extensions_mut();
+                        extensions.insert(KeycloakAuthStatus::Success);
+                        extensions.insert(RawClaims(authentication.claims));
+                        extensions.insert(authentication.roles);
+                        if let Some(groups) = authentication.groups {
+                            extensions.insert(groups);
+                        }
+                        if let Some(scopes) = authentication.scopes {
+                            extensions.insert(KeycloakScopes::new(scopes));
                         }
+                        if let Some(permissions) = authentication.permissions {
+                            extensions.insert(permissions);
+                        }
+                        extensions.insert(RequestErrorRenderer(error_renderer));
                     }
-                    Err(_) => {
-                        let e = AuthError::InvalidAuthorizationHeader;
-                        debug!("{}", &e);
-                        match self.passthrough_policy.policy(&e) {
-                            PassthroughAction::Pass => {
-                                {
-                                    let mut extensions = req.extensions_mut();
-                                    extensions.insert(KeycloakAuthStatus::Failure(e.clone()));
-                                }
-                                Box::pin(self.service.call(req).map(map_body_left))
+
+                    service.call(req).await.map(|res| res.map_into_left_body())
+                }
+                Err(e) => {
+                    debug!("{}", &e);
+                    match config.passthrough_policy.policy(&e) {
+                        PassthroughAction::Pass => {
+                            {
+                                let mut extensions = req.extensions_mut();
+                                extensions.insert(KeycloakAuthStatus::Failure(e.clone()));
+                                extensions.insert(RequestErrorRenderer(error_renderer));
                             }
-                            PassthroughAction::Return => Box::pin(ready(Ok(req.into_response(
-                                e.to_response(self.detailed_responses).map_into_right_body(),
-                            )))),
+                            service.call(req).await.map(|res| res.map_into_left_body())
+                        }
+                        PassthroughAction::Return => {
+                            let res = error_renderer.render(&e, &req);
+                            Ok(req.into_response(res.map_into_right_body()))
                         }
                     }
                 }
             }
-            None => {
-                let e = AuthError::NoAuthorizationHeader;
-                debug!("{}", &e);
-                match self.passthrough_policy.policy(&e) {
-                    PassthroughAction::Pass => {
-                        {
-                            let mut extensions = req.extensions_mut();
-                            extensions.insert(KeycloakAuthStatus::Failure(e.clone()));
-                        }
-                        Box::pin(self.service.call(req).map(map_body_left))
-                    }
-                    PassthroughAction::Return => Box::pin(ready(Ok(req.into_response(
-                        e.to_response(self.detailed_responses).map_into_right_body(),
-                    )))),
+        })
+    }
+}
+
+impl<PP: PassthroughPolicy> KeycloakAuth<PP> {
+    /// Get the renderer of the HTTP responses that are sent when authentication failed
+    fn error_renderer(&self) -> Arc<dyn ErrorRenderer> {
+        match &self.error_renderer {
+            Some(error_renderer) => Arc::clone(error_renderer),
+            None => Arc::new(DefaultErrorRenderer {
+                detailed_responses: self.detailed_responses,
+                response_format: self.response_format,
+                challenge_realm: self.challenge_realm.clone(),
+                unauthorized_on_malformed_header: self.unauthorized_on_malformed_header,
+            }),
+        }
+    }
+
+    /// Extract the JWT of a request, from the first token source that is present
+    fn extract_token(&self, req: &ServiceRequest) -> Result<String, AuthError> {
+        // A header that does not contain a bearer token (like `Authorization: Basic ...` added by a proxy) does not prevent the next sources from being tried
+        let mut first_error = None;
+        for source in &self.token_sources {
+            match source.extract(req) {
+                Ok(Some(token)) => {
+                    debug!("Bearer token was extracted from the {}", source);
+                    return Ok(token);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("No bearer token was extracted from the {} ({})", source, &e);
+                    first_error.get_or_insert(e);
                 }
             }
         }
+
+        Err(first_error.unwrap_or_else(|| {
+            AuthError::NoAuthorizationHeader(
+                self.token_sources.iter().map(|s| s.to_string()).collect(),
+            )
+        }))
     }
-}
 
-fn map_body_left<B, E>(
-    res: Result<ServiceResponse<B>, E>,
-) -> Result<ServiceResponse<EitherBody<B>>, E> {
-    res.map(|res| res.map_into_left_body())
+    /// Extract and verify (or introspect) the token of a request, returning its claims and Keycloak roles
+    async fn authenticate(&self, req: &ServiceRequest) -> Result<Authentication, AuthError> {
+        let token = self.extract_token(req)?;
+
+        let claims = self.validate_token(&token).await?;
+
+        if let Some(revocation_checker) = &self.revocation_checker {
+            if let Some(reason) = revocation_checker.check(&claims).await {
+                return Err(AuthError::Revoked(reason));
+            }
+            debug!("Token is not revoked");
+        }
+
+        if !self.issuers.is_empty() {
+            let issuer = claims.get("iss").and_then(Value::as_str);
+            if !issuer.is_some_and(|iss| self.issuers.iter().any(|i| i == iss)) {
+                return Err(AuthError::InvalidIssuer(issuer.map(str::to_owned)));
+            }
+            debug!("JWT issuer is valid");
+        }
+
+        if !self.audiences.is_empty() {
+            let audience_claims = from_value::<AudienceClaims>(claims.clone())
+                .map_err(|e| AuthError::DecodeError(e.to_string()))?;
+            let audiences = audience_claims.aud.unwrap_or_default();
+            if !audiences.iter().any(|aud| self.audiences.contains(aud)) {
+                return Err(AuthError::InvalidAudience(audiences));
+            }
+            debug!("JWT audience is valid");
+        }
+
+        let mut roles = vec![];
+        for source in &self.role_sources {
+            roles.extend(source.extract(&claims)?);
+        }
+        if let Some(role_mapping) = &self.role_mapping {
+            roles = role_mapping.expand(roles);
+        }
+        check_roles(&roles, &self.required_roles)?;
+        if let Some(role_requirement) = &self.role_requirement {
+            check_role_expr(&roles, role_requirement)?;
+        }
+
+        // Paths of the groups the user is a member of (if the group membership mapper is enabled in Keycloak)
+        let groups = match parse_groups(claims.get("groups")) {
+            Ok(groups) => {
+                check_groups(&groups, &self.required_groups)?;
+                Some(groups)
+            }
+            Err(e) if self.required_groups.is_empty() => {
+                debug!("Groups cannot be parsed ({})", e);
+                None
+            }
+            Err(e) => return Err(AuthError::DecodeError(e)),
+        };
+
+        let scopes = match parse_scopes(claims.get("scope")) {
+            Ok(scopes) => {
+                check_scopes(&scopes, &self.required_scopes)?;
+                Some(scopes)
+            }
+            Err(e) if self.required_scopes.is_empty() => {
+                debug!("Scopes cannot be parsed ({})", e);
+                None
+            }
+            Err(e) => return Err(AuthError::DecodeError(e)),
+        };
+
+        // Authorization data is only found in RPT, from Keycloak Authorization Services
+        let permissions = match parse_permissions(claims.get("authorization")) {
+            Ok(permissions) => {
+                check_permissions(&permissions, &self.required_permissions)?;
+                Some(permissions)
+            }
+            Err(e) if self.required_permissions.is_empty() => {
+                debug!("Permissions cannot be parsed ({})", e);
+                None
+            }
+            Err(e) => return Err(AuthError::DecodeError(e)),
+        };
+
+        for validator in &self.claim_validators {
+            validator.validate(&claims, req).await?;
+        }
+
+        Ok(Authentication {
+            claims,
+            roles,
+            groups,
+            scopes,
+            permissions,
+        })
+    }
+
+    /// Verify or introspect a token, returning its claims (from the validation cache, if possible)
+    async fn validate_token(&self, token: &str) -> Result<Value, AuthError> {
+        let cache = match &self.validation_cache {
+            Some(cache) => cache,
+            None => return self.validate_token_uncached(token).await,
+        };
+
+        let key = token_hash(token);
+        let now = self.clock.now();
+        if let Some(claims) = cache.get(&key, now).await {
+            debug!("Token validation result was found in cache");
+            if self.introspection.is_none() {
+                // The cache is bounded by the expiration date, but the other time claims may be checked differently (maximum age)
+                self.check_time_claims(&claims)?;
+            }
+            return Ok(claims);
+        }
+
+        let claims = self.validate_token_uncached(token).await?;
+        let expires_at = claims
+            .get("exp")
+            .and_then(Value::as_i64)
+            .and_then(|exp| Utc.timestamp_opt(exp, 0).single());
+        if let Some(expires_at) = expires_at {
+            cache.insert(&key, claims.clone(), now, expires_at).await;
+        }
+        Ok(claims)
+    }
+
+    /// Verify or introspect a token, returning its claims
+    async fn validate_token_uncached(&self, token: &str) -> Result<Value, AuthError> {
+        match &self.introspection {
+            Some(introspection) => {
+                let claims = introspection.introspect(token).await?;
+                debug!("Token is active");
+                Ok(claims)
+            }
+            None => self.verify_jwt(token).await,
+        }
+    }
+
+    /// Verify a JWT (signature and time claims), returning its claims
+    async fn verify_jwt(&self, token: &str) -> Result<Value, AuthError> {
+        let jwt_header = decode_header(token).map_err(|e| AuthError::InvalidJwt(e.to_string()))?;
+        debug!("JWT header was decoded");
+        debug!("JWT is using {:?} algorithm", &jwt_header.alg);
+
+        if !self.allowed_algorithms.is_empty() && !self.allowed_algorithms.contains(&jwt_header.alg)
+        {
+            return Err(AuthError::AlgorithmNotAllowed(jwt_header.alg));
+        }
+
+        let key = self
+            .keys
+            .find(jwt_header.kid.as_deref())
+            .await?
+            .ok_or_else(|| AuthError::UnknownKeyId(jwt_header.kid.clone()))?;
+        debug!("Key to verify JWT was found (kid: {:?})", &jwt_header.kid);
+
+        // The algorithm comes from the (untrusted) JWT header, but it was checked against the allowed algorithms, and `jsonwebtoken` rejects algorithms that do not belong to the family of the key
+        let mut validation = Validation::new(jwt_header.alg);
+        // Time claims are checked below
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        let raw_token = decode::<Value>(token, &key, &validation).map_err(|e| match e.kind() {
+            ErrorKind::InvalidAlgorithm => AuthError::AlgorithmNotAllowed(jwt_header.alg),
+            _ => AuthError::DecodeError(e.to_string()),
+        })?;
+        debug!("JWT was decoded");
+
+        self.check_time_claims(&raw_token.claims)?;
+        debug!("JWT time claims are valid");
+
+        Ok(raw_token.claims)
+    }
+
+    /// Check the expiration date, the "not before" date and the age of JWT claims
+    fn check_time_claims(&self, claims: &Value) -> Result<(), AuthError> {
+        let time_claims = from_value::<TimeClaims>(claims.clone())
+            .map_err(|e| AuthError::DecodeError(e.to_string()))?;
+        let now = self.clock.now().timestamp();
+        let leeway = self.leeway.as_secs() as i64;
+
+        if now > time_claims.exp + leeway {
+            return Err(AuthError::ExpiredJwt);
+        }
+        if self.validate_nbf && time_claims.nbf.is_some_and(|nbf| now + leeway < nbf) {
+            return Err(AuthError::NotYetValidJwt);
+        }
+        if let Some(max_age) = self.max_age {
+            let max_age = max_age.as_secs() as i64;
+            if time_claims
+                .iat
+                .is_none_or(|iat| now - leeway > iat + max_age)
+            {
+                return Err(AuthError::TooOldJwt);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,239 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+
+use super::roles::ParseRoleError;
+use super::Role;
+
+/// A table of roles that imply other roles (for example, to expand composite roles that Keycloak does not include in JWT)
+///
+/// Implied roles are expanded transitively, and are added to the roles extracted from JWT before they are checked.
+/// The table cannot contain cycles: they are detected when it is built.
+///
+/// It can be loaded from a JSON object whose keys and values are roles in their string form (see [Role](Role)):
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{Role, RoleMapping};
+///
+/// let mapping = RoleMapping::from_json(r#"{
+///     "admin": ["user", "backoffice.admin"],
+///     "backoffice.admin": ["backoffice.readonly"]
+/// }"#).unwrap();
+///
+/// let roles = mapping.expand(vec![Role::Realm { role: "admin".to_owned() }]);
+/// assert_eq!(
+///     roles.iter().map(|r| r.to_string()).collect::<Vec<String>>(),
+///     vec!["admin", "user", "backoffice.admin", "backoffice.readonly"]
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RoleMapping {
+    /// Every role implied by a role, transitively
+    implied_roles: HashMap<Role, Vec<Role>>,
+}
+
+impl RoleMapping {
+    /// Build a table from roles and the roles they directly imply
+    pub fn new<T: IntoIterator<Item = (Role, Vec<Role>)>>(
+        mapping: T,
+    ) -> Result<Self, RoleMappingError> {
+        let mapping: HashMap<Role, Vec<Role>> = mapping.into_iter().collect();
+        let mut implied_roles = HashMap::new();
+        for role in mapping.keys() {
+            expand(role, &mapping, &mut vec![], &mut implied_roles)?;
+        }
+        implied_roles.retain(|role, _| mapping.contains_key(role));
+        Ok(Self { implied_roles })
+    }
+
+    /// Load a table from a JSON object (see [RoleMapping](RoleMapping))
+    pub fn from_json(json: &str) -> Result<Self, RoleMappingError> {
+        let mapping = serde_json::from_str::<HashMap<String, Vec<Role>>>(json)
+            .map_err(|e| RoleMappingError::Parse(e.to_string()))?;
+        Self::from_strings(mapping)
+    }
+
+    /// Build a table whose keys are roles in their string form
+    fn from_strings(mapping: HashMap<String, Vec<Role>>) -> Result<Self, RoleMappingError> {
+        let mapping = mapping
+            .into_iter()
+            .map(|(role, implied)| Ok((role.parse()?, implied)))
+            .collect::<Result<Vec<(Role, Vec<Role>)>, ParseRoleError>>()
+            .map_err(|e| RoleMappingError::Parse(e.to_string()))?;
+        Self::new(mapping)
+    }
+
+    /// Add the roles implied by the provided roles (without duplicates, in order)
+    pub fn expand(&self, roles: Vec<Role>) -> Vec<Role> {
+        let mut seen = HashSet::new();
+        let mut expanded = vec![];
+        for role in roles {
+            let implied = self.implied_roles.get(&role).cloned().unwrap_or_default();
+            for role in std::iter::once(role).chain(implied) {
+                if seen.insert(role.clone()) {
+                    expanded.push(role);
+                }
+            }
+        }
+        expanded
+    }
+}
+
+/// Collect the roles implied by a role, in depth-first order, failing if a role of the current path is reached again
+///
+/// The roles implied by each role are only collected once (and reused when roles share implied roles).
+fn expand(
+    role: &Role,
+    mapping: &HashMap<Role, Vec<Role>>,
+    path: &mut Vec<Role>,
+    implied_roles: &mut HashMap<Role, Vec<Role>>,
+) -> Result<(), RoleMappingError> {
+    if implied_roles.contains_key(role) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|r| r == role) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(role.clone());
+        return Err(RoleMappingError::Cycle(cycle));
+    }
+
+    path.push(role.clone());
+    let mut seen = HashSet::new();
+    let mut expanded = vec![];
+    for implied in mapping.get(role).into_iter().flatten() {
+        expand(implied, mapping, path, implied_roles)?;
+        for role in std::iter::once(implied).chain(&implied_roles[implied]) {
+            if seen.insert(role.clone()) {
+                expanded.push(role.clone());
+            }
+        }
+    }
+    path.pop();
+    implied_roles.insert(role.clone(), expanded);
+    Ok(())
+}
+
+impl<'de> Deserialize<'de> for RoleMapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mapping = HashMap::<String, Vec<Role>>::deserialize(deserializer)?;
+        Self::from_strings(mapping).map_err(serde::de::Error::custom)
+    }
+}
+
+/// An error that happened while building a role mapping table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoleMappingError {
+    /// Roles imply each other (the cycle is described from a role back to itself)
+    Cycle(Vec<Role>),
+    /// The table cannot be parsed
+    Parse(String),
+}
+
+impl std::fmt::Display for RoleMappingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cycle(cycle) => write!(
+                f,
+                "Role mapping contains a cycle ({})",
+                cycle
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            ),
+            Self::Parse(e) => write!(f, "Cannot parse role mapping ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for RoleMappingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(role: &str) -> Role {
+        role.parse().unwrap()
+    }
+
+    #[test]
+    fn transitive_expansion() {
+        let mapping = RoleMapping::new(vec![
+            (role("admin"), vec![role("editor"), role("client.admin")]),
+            (role("editor"), vec![role("viewer")]),
+            (role("client.admin"), vec![role("viewer")]),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            mapping.expand(vec![role("other"), role("admin"), role("viewer")]),
+            vec![
+                role("other"),
+                role("admin"),
+                role("editor"),
+                role("viewer"),
+                role("client.admin"),
+            ]
+        );
+        assert_eq!(mapping.expand(vec![]), vec![]);
+    }
+
+    #[test]
+    fn shared_implied_roles() {
+        // Each level implies both roles of the next level: expanding every path would take 2^40 steps
+        let levels = 40;
+        let mapping = RoleMapping::new((0..levels).flat_map(|level| {
+            let next = vec![
+                role(&format!("l{}", level + 1)),
+                role(&format!("r{}", level + 1)),
+            ];
+            vec![
+                (role(&format!("l{}", level)), next.clone()),
+                (role(&format!("r{}", level)), next),
+            ]
+        }))
+        .unwrap();
+
+        let roles = mapping.expand(vec![role("l0")]);
+        assert_eq!(roles.len(), 2 * levels + 1);
+        assert_eq!(
+            roles[..5],
+            [role("l0"), role("l1"), role("l2"), role("l3"), role("l4")]
+        );
+    }
+
+    #[test]
+    fn cycles() {
+        assert_eq!(
+            RoleMapping::new(vec![(role("a"), vec![role("a")])]),
+            Err(RoleMappingError::Cycle(vec![role("a"), role("a")]))
+        );
+
+        let error = RoleMapping::from_json(r#"{"a": ["b"], "b": ["c"], "c": ["a"], "d": []}"#);
+        assert!(matches!(error, Err(RoleMappingError::Cycle(cycle)) if cycle.len() == 4));
+
+        let error = serde_json::from_str::<RoleMapping>(r#"{"a": ["b"], "b": ["a"]}"#).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Role mapping contains a cycle"));
+    }
+
+    #[test]
+    fn from_json() {
+        let mapping = RoleMapping::from_json(r#"{"my\\.app.admin": ["realm:user"]}"#).unwrap();
+        assert_eq!(
+            mapping.expand(vec![Role::Client {
+                client: "my.app".to_owned(),
+                role: "admin".to_owned(),
+            }]),
+            vec![role("my\\.app.admin"), role("user")]
+        );
+
+        assert!(RoleMapping::from_json(r#"{"a": "b"}"#).is_err());
+        assert!(RoleMapping::from_json(r#"{"a.": ["b"]}"#).is_err());
+    }
+}
@@ -2,10 +2,26 @@ use paperclip::actix::OperationModifier;
 use paperclip::v2::schema::Apiv2Schema;
 use serde::de::DeserializeOwned;
 
-use super::extractors::{KeycloakClaims, KeycloakRoles};
+use super::extractors::{
+    KeycloakClaims, KeycloakGroups, KeycloakPermissions, KeycloakRoles, KeycloakScopes,
+    RequireRoles,
+};
+use super::roles::RoleRequirement;
 
 impl<T: DeserializeOwned> Apiv2Schema for KeycloakClaims<T> {}
 impl<T: DeserializeOwned> OperationModifier for KeycloakClaims<T> {}
 
 impl Apiv2Schema for KeycloakRoles {}
 impl OperationModifier for KeycloakRoles {}
+
+impl Apiv2Schema for KeycloakGroups {}
+impl OperationModifier for KeycloakGroups {}
+
+impl Apiv2Schema for KeycloakScopes {}
+impl OperationModifier for KeycloakScopes {}
+
+impl Apiv2Schema for KeycloakPermissions {}
+impl OperationModifier for KeycloakPermissions {}
+
+impl<R: RoleRequirement> Apiv2Schema for RequireRoles<R> {}
+impl<R: RoleRequirement> OperationModifier for RequireRoles<R> {}
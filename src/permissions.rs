@@ -0,0 +1,165 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::errors::AuthError;
+
+/// A permission granted by Keycloak Authorization Services, as found in the `authorization.permissions` claim of RPT (requesting party tokens)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Permission {
+    /// ID of the resource
+    pub rsid: Option<String>,
+    /// Name of the resource
+    pub rsname: Option<String>,
+    /// Scopes that are granted on the resource
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl Permission {
+    /// Check if the permission grants access to a resource (identified by its name or ID), with a scope (if any)
+    pub fn grants(&self, resource: &str, scope: Option<&str>) -> bool {
+        let is_resource =
+            self.rsname.as_deref() == Some(resource) || self.rsid.as_deref() == Some(resource);
+        is_resource && scope.is_none_or(|scope| self.scopes.iter().any(|s| s == scope))
+    }
+}
+
+/// A permission that must be granted: a resource (identified by its name or ID), with a scope (if any)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PermissionRequirement {
+    /// Name or ID of the resource
+    pub resource: String,
+    /// Scope that must be granted on the resource (if none, any permission on the resource is enough)
+    pub scope: Option<String>,
+}
+
+impl PermissionRequirement {
+    /// Require a permission on a resource, whatever its scopes
+    pub fn resource(resource: &str) -> Self {
+        Self {
+            resource: resource.to_owned(),
+            scope: None,
+        }
+    }
+
+    /// Require a permission on a resource with a scope
+    pub fn resource_scope(resource: &str, scope: &str) -> Self {
+        Self {
+            resource: resource.to_owned(),
+            scope: Some(scope.to_owned()),
+        }
+    }
+
+    /// Check if the requirement is satisfied by some permissions
+    pub fn is_satisfied_by(&self, permissions: &[Permission]) -> bool {
+        permissions
+            .iter()
+            .any(|permission| permission.grants(&self.resource, self.scope.as_deref()))
+    }
+}
+
+/// A requirement is displayed as `resource` or `resource#scope`
+impl std::fmt::Display for PermissionRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.scope {
+            Some(scope) => write!(f, "{}#{}", self.resource, scope),
+            None => f.write_str(&self.resource),
+        }
+    }
+}
+
+/// Parse the permissions of an `authorization` claim (a missing or null claim means that there are no permissions)
+pub fn parse_permissions(authorization: Option<&Value>) -> Result<Vec<Permission>, String> {
+    match authorization {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(Value::Object(authorization)) => match authorization.get("permissions") {
+            None | Some(Value::Null) => Ok(vec![]),
+            Some(permissions) => {
+                Vec::<Permission>::deserialize(permissions).map_err(|e| e.to_string())
+            }
+        },
+        Some(authorization) => Err(format!("invalid authorization claim: {}", authorization)),
+    }
+}
+
+pub fn check_permissions(
+    permissions: &[Permission],
+    required_permissions: &[PermissionRequirement],
+) -> Result<(), AuthError> {
+    debug!("JWT contains {} permissions", permissions.len());
+
+    let missing_permissions: Vec<PermissionRequirement> = required_permissions
+        .iter()
+        .filter(|requirement| !requirement.is_satisfied_by(permissions))
+        .cloned()
+        .collect();
+
+    if missing_permissions.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::MissingPermissions(missing_permissions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn authorization_claim() {
+        assert_eq!(
+            parse_permissions(Some(&json!({ "permissions": [{ "rsname": "orders" }] }))),
+            Ok(vec![Permission {
+                rsid: None,
+                rsname: Some("orders".to_owned()),
+                scopes: vec![],
+            }])
+        );
+        assert_eq!(parse_permissions(None), Ok(vec![]));
+        assert_eq!(parse_permissions(Some(&Value::Null)), Ok(vec![]));
+        assert_eq!(parse_permissions(Some(&json!({}))), Ok(vec![]));
+        assert!(parse_permissions(Some(&json!("orders"))).is_err());
+        assert!(parse_permissions(Some(&json!({ "permissions": "orders" }))).is_err());
+    }
+
+    #[test]
+    fn required_permissions() {
+        let permissions: Vec<Permission> = serde_json::from_value(json!([
+            { "rsid": "1b2c", "rsname": "orders", "scopes": ["read", "write"] },
+            { "rsid": "3d4e", "rsname": "invoices" },
+        ]))
+        .unwrap();
+
+        assert!(check_permissions(&permissions, &[]).is_ok());
+        assert!(check_permissions(
+            &permissions,
+            &[
+                PermissionRequirement::resource_scope("orders", "write"),
+                PermissionRequirement::resource_scope("1b2c", "read"),
+                PermissionRequirement::resource("invoices"),
+            ]
+        )
+        .is_ok());
+        assert_eq!(
+            check_permissions(
+                &permissions,
+                &[
+                    PermissionRequirement::resource_scope("invoices", "read"),
+                    PermissionRequirement::resource("orders"),
+                    PermissionRequirement::resource("customers"),
+                ]
+            ),
+            Err(AuthError::MissingPermissions(vec![
+                PermissionRequirement::resource_scope("invoices", "read"),
+                PermissionRequirement::resource("customers"),
+            ]))
+        );
+    }
+}
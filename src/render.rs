@@ -0,0 +1,154 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderValue, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::HttpMessage;
+use actix_web::{HttpResponse, ResponseError};
+use log::debug;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::errors::{AuthError, ResponseFormat};
+
+/// Something that builds the HTTP response that is sent when authentication fails
+///
+/// A renderer can be defined using a closure:
+///
+/// ```
+/// use actix_web::dev::ServiceRequest;
+/// use actix_web::HttpResponse;
+/// use actix_web::ResponseError;
+/// use actix_web_middleware_keycloak_auth::{AuthError, ErrorRenderer};
+/// use serde_json::json;
+///
+/// let renderer = |e: &AuthError, req: &ServiceRequest| {
+///     HttpResponse::build(e.status_code()).json(json!({
+///         "error": { "code": e.code(), "path": req.path() }
+///     }))
+/// };
+/// # fn check<R: ErrorRenderer>(_: R) {}
+/// # check(renderer);
+/// ```
+pub trait ErrorRenderer: Send + Sync {
+    /// Build the HTTP response for an authentication error
+    fn render(&self, error: &AuthError, req: &ServiceRequest) -> HttpResponse;
+}
+
+/// An error renderer can be defined using a closure
+impl<F> ErrorRenderer for F
+where
+    F: Fn(&AuthError, &ServiceRequest) -> HttpResponse + Send + Sync,
+{
+    fn render(&self, error: &AuthError, req: &ServiceRequest) -> HttpResponse {
+        self(error, req)
+    }
+}
+
+/// An error renderer that responds with the HTTP status only (i.e. no details about the error)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlainTextErrorRenderer;
+
+impl ErrorRenderer for PlainTextErrorRenderer {
+    fn render(&self, error: &AuthError, _req: &ServiceRequest) -> HttpResponse {
+        error.to_response(false)
+    }
+}
+
+/// An error renderer that responds with a plain text description of the error
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DetailedErrorRenderer;
+
+impl ErrorRenderer for DetailedErrorRenderer {
+    fn render(&self, error: &AuthError, _req: &ServiceRequest) -> HttpResponse {
+        error.to_response(true)
+    }
+}
+
+/// An error renderer that responds with a `application/problem+json` body (see [AuthError::to_problem_response](AuthError::to_problem_response))
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProblemJsonErrorRenderer {
+    /// If true, the body will contain details that explain what went wrong
+    pub detailed_responses: bool,
+}
+
+impl ErrorRenderer for ProblemJsonErrorRenderer {
+    fn render(&self, error: &AuthError, req: &ServiceRequest) -> HttpResponse {
+        error.to_problem_response(self.detailed_responses, &correlation_id(req))
+    }
+}
+
+/// The error renderer that is used when no custom renderer is set: it follows the settings of the middleware
+///
+/// The body is built according to `detailed_responses` and `response_format`, and 401 and 403 responses get a `WWW-Authenticate` header.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultErrorRenderer {
+    /// If true, error responses will be more detailed to explain what went wrong
+    pub detailed_responses: bool,
+    /// Format of error responses
+    pub response_format: ResponseFormat,
+    /// Realm that is announced in the `WWW-Authenticate` header of error responses (if any)
+    pub challenge_realm: Option<String>,
+    /// If true, requests with a malformed `Authorization` header get a 401 error instead of a 400 error
+    pub unauthorized_on_malformed_header: bool,
+}
+
+impl ErrorRenderer for DefaultErrorRenderer {
+    fn render(&self, error: &AuthError, req: &ServiceRequest) -> HttpResponse {
+        let status = match error {
+            AuthError::InvalidAuthorizationHeader if self.unauthorized_on_malformed_header => {
+                StatusCode::UNAUTHORIZED
+            }
+            _ => error.status_code(),
+        };
+
+        let mut res = match self.response_format {
+            ResponseFormat::PlainText => error.plain_text_response(status, self.detailed_responses),
+            ResponseFormat::ProblemJson => {
+                error.problem_response(status, self.detailed_responses, &correlation_id(req))
+            }
+        };
+
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            let challenge =
+                error.www_authenticate(self.challenge_realm.as_deref(), self.detailed_responses);
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                res.headers_mut().insert(WWW_AUTHENTICATE, value);
+            }
+        }
+
+        res
+    }
+}
+
+/// Error renderer of the middleware, that is injected in request-local data so that role checks done later respond the same way
+#[derive(Clone)]
+pub(crate) struct RequestErrorRenderer(pub Arc<dyn ErrorRenderer>);
+
+/// Build the HTTP response for an error that happened after the middleware, using the error renderer of the middleware (or the default one)
+pub(crate) fn render_after_middleware(error: &AuthError, req: &ServiceRequest) -> HttpResponse {
+    let error_renderer = req.extensions().get::<RequestErrorRenderer>().cloned();
+    match error_renderer {
+        Some(RequestErrorRenderer(error_renderer)) => error_renderer.render(error, req),
+        None => DefaultErrorRenderer {
+            detailed_responses: true,
+            ..DefaultErrorRenderer::default()
+        }
+        .render(error, req),
+    }
+}
+
+/// Get the ID of a request (from the `X-Request-Id` header), or generate one, so that errors can be correlated with logs
+pub(crate) fn correlation_id(req: &ServiceRequest) -> String {
+    let correlation_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    debug!("Correlation ID of the error is {}", &correlation_id);
+    correlation_id
+}
@@ -0,0 +1,273 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use chrono::{DateTime, Utc};
+use futures_util::future::{ready, LocalBoxFuture};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::clock::{Clock, SystemClock};
+
+/// Something that can tell whether a token was revoked (for example using a deny list), given its claims
+///
+/// It is called on every request, after the token is validated (even when its validation result comes from a cache).
+/// A checker can be defined using a closure that returns a future of the reason why the token is revoked (if it is):
+///
+/// ```
+/// use serde_json::Value;
+///
+/// let checker = |claims: &Value| {
+///     let is_revoked = claims.get("sub").and_then(Value::as_str) == Some("disabled-user");
+///     async move { is_revoked.then(|| "the user is disabled".to_owned()) }
+/// };
+/// # fn check<C: actix_web_middleware_keycloak_auth::RevocationChecker>(_: C) {}
+/// # check(checker);
+/// ```
+pub trait RevocationChecker: Send + Sync {
+    /// Check whether a token was revoked, returning the reason why it is (if it is)
+    fn check(&self, claims: &Value) -> LocalBoxFuture<'static, Option<String>>;
+}
+
+/// A revocation checker can be defined using a closure
+impl<F, Fut> RevocationChecker for F
+where
+    F: Fn(&Value) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<String>> + 'static,
+{
+    fn check(&self, claims: &Value) -> LocalBoxFuture<'static, Option<String>> {
+        Box::pin(self(claims))
+    }
+}
+
+/// An in-memory deny list of tokens (`jti` claim), sessions (`sid` claim) and subjects (`sub` claim)
+///
+/// Clones of a `RevocationList` share the same deny list, so it can be updated while the middleware uses it.
+/// Tokens and sessions are revoked until an expiration date (after which they cannot be used anyway): expired entries are removed when the list is updated.
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{KeycloakAuth, DecodingKey, RevocationList};
+/// use chrono::{Duration, Utc};
+/// use std::sync::Arc;
+///
+/// # const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAnzyis1ZjfNB0bBgKFMSv\nvkTtwlvBsaJq7S5wA+kzeVOVpVWwkWdVha4s38XM/pa/yr47av7+z3VTmvDRyAHc\naT92whREFpLv9cj5lTeJSibyr/Mrm/YtjCZVWgaOYIhwrXwKLqPr/11inWsAkfIy\ntvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0\ne+lf4s4OxQawWD79J9/5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWb\nV6L11BWkpzGXSW4Hv43qa+GSYOD2QU68Mb59oSk2OB+BtOLpJofmbGEGgvmwyCI9\nMwIDAQAB\n-----END PUBLIC KEY-----";
+/// let revocation_list = RevocationList::default();
+/// let keycloak_auth = KeycloakAuth {
+///     revocation_checker: Some(Arc::new(revocation_list.clone())),
+///     ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+/// };
+///
+/// // Later, when a session is closed (until the maximal lifetime of sessions, plus the leeway of the middleware)
+/// revocation_list.revoke_session(
+///     "8f4f3d1a-1b1e-4b8e-9a47-0f7c2b4f5a6e",
+///     Utc::now() + Duration::hours(10) + Duration::minutes(1),
+/// );
+///
+/// // Later, when a user is disabled
+/// revocation_list.revoke_subject("13b9bb9e-e2fa-4ccf-a4c3-4ecc8b1b3a44");
+/// ```
+#[derive(Clone)]
+pub struct RevocationList {
+    inner: Arc<RwLock<DenyList>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug, Default)]
+struct DenyList {
+    /// Revoked token IDs, with the date when they can be forgotten
+    token_ids: HashMap<String, DateTime<Utc>>,
+    /// Revoked session IDs, with the date when they can be forgotten
+    session_ids: HashMap<String, DateTime<Utc>>,
+    subjects: HashSet<String>,
+}
+
+impl DenyList {
+    /// Remove the tokens and sessions whose revocation has expired
+    fn prune(&mut self, now: DateTime<Utc>) {
+        self.token_ids.retain(|_, expires_at| *expires_at > now);
+        self.session_ids.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+impl RevocationList {
+    /// Create an empty deny list that uses the provided clock to expire entries (the clock of the system is used by default)
+    pub fn with_clock<C: Clock + 'static>(clock: C) -> Self {
+        Self {
+            inner: Arc::default(),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// Revoke a token, given its ID (`jti` claim), until the provided date (its expiration date, plus the leeway of the middleware)
+    pub fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) {
+        let mut deny_list = self.write();
+        deny_list.token_ids.insert(jti.to_owned(), expires_at);
+    }
+
+    /// Stop revoking a token, given its ID (`jti` claim)
+    pub fn restore_token(&self, jti: &str) {
+        self.write().token_ids.remove(jti);
+    }
+
+    /// Revoke every token of a session, given its ID (`sid` claim), until the provided date (when the session cannot be used anymore, plus the leeway of the middleware)
+    pub fn revoke_session(&self, sid: &str, expires_at: DateTime<Utc>) {
+        let mut deny_list = self.write();
+        deny_list.session_ids.insert(sid.to_owned(), expires_at);
+    }
+
+    /// Stop revoking the tokens of a session, given its ID (`sid` claim)
+    pub fn restore_session(&self, sid: &str) {
+        self.write().session_ids.remove(sid);
+    }
+
+    /// Revoke every token of a subject (`sub` claim), for example a user that was disabled
+    pub fn revoke_subject(&self, sub: &str) {
+        self.write().subjects.insert(sub.to_owned());
+    }
+
+    /// Stop revoking the tokens of a subject (`sub` claim), for example a user that was enabled again
+    pub fn restore_subject(&self, sub: &str) {
+        self.write().subjects.remove(sub);
+    }
+
+    /// Check whether a token was revoked, returning the reason why it is (if it is)
+    fn revocation(&self, claims: &Value) -> Option<String> {
+        let now = self.clock.now();
+        let deny_list = self.read();
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+        let is_revoked = |entries: &HashMap<String, DateTime<Utc>>, id: &str| {
+            entries.get(id).is_some_and(|expires_at| *expires_at > now)
+        };
+
+        if claim("jti").is_some_and(|jti| is_revoked(&deny_list.token_ids, jti)) {
+            Some("the token was revoked".to_owned())
+        } else if claim("sid").is_some_and(|sid| is_revoked(&deny_list.session_ids, sid)) {
+            Some("the session was revoked".to_owned())
+        } else if claim("sub").is_some_and(|sub| deny_list.subjects.contains(sub)) {
+            Some("the subject was revoked".to_owned())
+        } else {
+            None
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, DenyList> {
+        // The deny list stays consistent even if a thread panicked while holding the lock
+        self.inner.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the deny list to update it (expired entries are removed first)
+    fn write(&self) -> RwLockWriteGuard<'_, DenyList> {
+        let mut deny_list = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        deny_list.prune(self.clock.now());
+        deny_list
+    }
+}
+
+impl Default for RevocationList {
+    fn default() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl std::fmt::Debug for RevocationList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RevocationList")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RevocationChecker for RevocationList {
+    fn check(&self, claims: &Value) -> LocalBoxFuture<'static, Option<String>> {
+        Box::pin(ready(self.revocation(claims)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FixedClock;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn deny_list() {
+        let revocation_list = RevocationList::with_clock(FixedClock::new(at(0)));
+        let claims = json!({ "jti": "token", "sid": "session", "sub": "user" });
+        assert_eq!(revocation_list.revocation(&claims), None);
+        assert_eq!(revocation_list.revocation(&json!({})), None);
+
+        revocation_list.clone().revoke_subject("user");
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the subject was revoked".to_owned())
+        );
+        revocation_list.revoke_session("session", at(60));
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the session was revoked".to_owned())
+        );
+        revocation_list.revoke_token("token", at(60));
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the token was revoked".to_owned())
+        );
+        assert_eq!(
+            revocation_list.revocation(&json!({ "jti": "other", "sid": "other", "sub": "user" })),
+            Some("the subject was revoked".to_owned())
+        );
+
+        revocation_list.restore_subject("user");
+        assert_eq!(
+            revocation_list.revocation(&json!({ "jti": "other", "sid": "other", "sub": "user" })),
+            None
+        );
+        revocation_list.restore_token("token");
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the session was revoked".to_owned())
+        );
+        revocation_list.restore_session("session");
+        assert_eq!(revocation_list.revocation(&claims), None);
+    }
+
+    #[test]
+    fn expiration() {
+        let clock = FixedClock::new(at(0));
+        let revocation_list = RevocationList::with_clock(clock.clone());
+        let claims = json!({ "jti": "token", "sid": "session" });
+
+        revocation_list.revoke_token("token", at(30));
+        revocation_list.revoke_session("session", at(60));
+        revocation_list.revoke_token("other", at(120));
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the token was revoked".to_owned())
+        );
+
+        clock.set(at(30));
+        assert_eq!(
+            revocation_list.revocation(&claims),
+            Some("the session was revoked".to_owned())
+        );
+        clock.set(at(60));
+        assert_eq!(revocation_list.revocation(&claims), None);
+
+        // Expired entries are removed when the list is updated
+        assert_eq!(revocation_list.read().token_ids.len(), 2);
+        revocation_list.revoke_subject("user");
+        let deny_list = revocation_list.read();
+        assert_eq!(
+            deny_list.token_ids.keys().collect::<Vec<&String>>(),
+            vec!["other"]
+        );
+        assert!(deny_list.session_ids.is_empty());
+    }
+}
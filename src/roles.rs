@@ -4,12 +4,482 @@
 // License: MIT
 
 use log::debug;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{from_value, Value};
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use super::errors::AuthError;
 use super::{Access, Role};
 
+/// A combination of roles that must be included in JWT
+///
+/// A `Vec<Role>` can be converted into an expression that requires all of the roles.
+///
+/// Expressions can be parsed from (and serialized to) strings like `all(any(admin, backoffice.readonly), not(banned))`, where roles follow the syntax of [Role](Role)'s [FromStr](FromStr) implementation.
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{Role, RoleExpr};
+///
+/// // "admin" realm role, or both "readonly" and "export" roles of the "backoffice" client, but not the "banned" realm role
+/// let expr = RoleExpr::all(vec![
+///     RoleExpr::any(vec![
+///         RoleExpr::realm("admin"),
+///         RoleExpr::all(vec![
+///             RoleExpr::client("backoffice", "readonly"),
+///             RoleExpr::client("backoffice", "export"),
+///         ]),
+///     ]),
+///     RoleExpr::not(RoleExpr::realm("banned")),
+/// ]);
+///
+/// assert!(expr.is_satisfied_by(&[Role::Realm { role: "admin".to_owned() }]));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoleExpr {
+    /// A role that must be included
+    Role(Role),
+    /// Expressions that must all be satisfied (an empty list is always satisfied)
+    All(Vec<RoleExpr>),
+    /// Expressions at least one of which must be satisfied (an empty list is never satisfied)
+    Any(Vec<RoleExpr>),
+    /// An expression that must not be satisfied
+    Not(Box<RoleExpr>),
+}
+
+impl RoleExpr {
+    /// Require a realm role
+    pub fn realm(role: &str) -> Self {
+        Self::Role(Role::Realm {
+            role: role.to_owned(),
+        })
+    }
+
+    /// Require a client role
+    pub fn client(client: &str, role: &str) -> Self {
+        Self::Role(Role::Client {
+            client: client.to_owned(),
+            role: role.to_owned(),
+        })
+    }
+
+    /// Require all expressions to be satisfied
+    pub fn all(exprs: Vec<RoleExpr>) -> Self {
+        Self::All(exprs)
+    }
+
+    /// Require at least one expression to be satisfied
+    pub fn any(exprs: Vec<RoleExpr>) -> Self {
+        Self::Any(exprs)
+    }
+
+    /// Require an expression not to be satisfied
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(expr: RoleExpr) -> Self {
+        Self::Not(Box::new(expr))
+    }
+
+    /// Check if the provided roles satisfy the expression
+    pub fn is_satisfied_by(&self, roles: &[Role]) -> bool {
+        self.unsatisfied(roles).is_none()
+    }
+
+    /// Get the part of the expression that is not satisfied by the provided roles (if any)
+    ///
+    /// For an all-of expression, only the unsatisfied sub-expressions are kept.
+    /// Any-of and negated expressions are returned as a whole.
+    pub fn unsatisfied(&self, roles: &[Role]) -> Option<RoleExpr> {
+        match self {
+            Self::Role(role) => (!roles.contains(role)).then(|| self.clone()),
+            Self::All(exprs) => {
+                let unsatisfied: Vec<RoleExpr> = exprs
+                    .iter()
+                    .filter_map(|expr| expr.unsatisfied(roles))
+                    .collect();
+                (!unsatisfied.is_empty()).then_some(Self::All(unsatisfied))
+            }
+            Self::Any(exprs) => {
+                (!exprs.iter().any(|expr| expr.is_satisfied_by(roles))).then(|| self.clone())
+            }
+            Self::Not(expr) => expr.is_satisfied_by(roles).then(|| self.clone()),
+        }
+    }
+
+    /// Top-level requirements of the expression (the sub-expressions of an all-of expression, or the expression itself)
+    pub(crate) fn requirements(&self) -> Vec<&RoleExpr> {
+        match self {
+            Self::All(exprs) => exprs.iter().collect(),
+            _ => vec![self],
+        }
+    }
+}
+
+impl From<Role> for RoleExpr {
+    fn from(role: Role) -> Self {
+        Self::Role(role)
+    }
+}
+
+/// A list of roles is a shorthand for an expression that requires all of them
+impl From<Vec<Role>> for RoleExpr {
+    fn from(roles: Vec<Role>) -> Self {
+        Self::All(roles.into_iter().map(Self::Role).collect())
+    }
+}
+
+impl std::fmt::Display for RoleExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |exprs: &[RoleExpr]| {
+            exprs
+                .iter()
+                .map(|expr| expr.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        match self {
+            Self::Role(role) => write!(f, "{}", role),
+            Self::All(exprs) => write!(f, "all({})", join(exprs)),
+            Self::Any(exprs) => write!(f, "any({})", join(exprs)),
+            Self::Not(expr) => write!(f, "not({})", expr),
+        }
+    }
+}
+
+/// A type that describes the roles that are required to extract a [RequireRoles](super::RequireRoles)
+///
+/// It is usually implemented by marker types, with the [role_requirement](crate::role_requirement) macro:
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{role_requirement, RoleExpr, RoleRequirement};
+///
+/// role_requirement!(
+///     /// The "admin" realm role
+///     pub AdminRole = "admin"
+/// );
+///
+/// // Which is equivalent to:
+/// pub struct ManualAdminRole;
+///
+/// impl RoleRequirement for ManualAdminRole {
+///     fn role_requirement() -> RoleExpr {
+///         RoleExpr::realm("admin")
+///     }
+/// }
+///
+/// assert_eq!(AdminRole::role_requirement(), ManualAdminRole::role_requirement());
+/// ```
+pub trait RoleRequirement {
+    /// Roles that must be included in JWT
+    fn role_requirement() -> RoleExpr;
+}
+
+/// Define a marker type that implements [RoleRequirement](RoleRequirement), from a role expression string (see [RoleExpr](RoleExpr))
+///
+/// The expression is parsed the first time roles are checked (and then reused): it panics if the expression is invalid.
+/// Call `role_requirement()` when the application starts to detect invalid expressions early.
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::role_requirement;
+///
+/// role_requirement!(pub BackofficeEditor = "all(backoffice.readonly, backoffice.write)");
+/// role_requirement!(NotBanned = "not(banned)");
+///
+/// // At startup
+/// # use actix_web_middleware_keycloak_auth::RoleRequirement;
+/// BackofficeEditor::role_requirement();
+/// NotBanned::role_requirement();
+/// ```
+#[macro_export]
+macro_rules! role_requirement {
+    ($(#[$meta:meta])* $vis:vis $name:ident = $expr:literal) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name;
+
+        impl $crate::RoleRequirement for $name {
+            fn role_requirement() -> $crate::RoleExpr {
+                static EXPR: ::std::sync::OnceLock<$crate::RoleExpr> = ::std::sync::OnceLock::new();
+                EXPR.get_or_init(|| {
+                    $expr
+                        .parse()
+                        .expect(concat!("invalid role expression: ", $expr))
+                })
+                .clone()
+            }
+        }
+    };
+}
+
+impl FromStr for RoleExpr {
+    type Err = ParseRoleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        let expr = parser.expr()?;
+        parser.end()?;
+        Ok(expr)
+    }
+}
+
+impl Serialize for RoleExpr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RoleExpr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parse a role from a string
+///
+/// - `admin` or `realm:admin` is the "admin" realm role
+/// - `backoffice.readonly` is the "readonly" role of the "backoffice" client (the client ID ends at the first dot)
+///
+/// A backslash escapes the next character: for example, `my\.app.readonly` is the "readonly" role of the "my.app" client.
+/// Dots, backslashes, commas, parentheses and whitespace must be escaped in client IDs and realm roles (this is what the [Display](std::fmt::Display) implementation does).
+impl FromStr for Role {
+    type Err = ParseRoleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(s);
+        parser.skip_whitespace();
+        let start = parser.offset();
+        let token = parser.token()?;
+        let role = parser.role(token, start)?;
+        parser.end()?;
+        Ok(role)
+    }
+}
+
+/// Escape a client ID or role name so that it can be parsed back
+pub(crate) fn escape(name: &str, escape_dots: bool) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c == '\\'
+            || c == ','
+            || c == '('
+            || c == ')'
+            || c.is_whitespace()
+            || (escape_dots && c == '.')
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    if escaped.starts_with(REALM_PREFIX) {
+        escaped.insert(REALM_PREFIX.len() - 1, '\\');
+    }
+    escaped
+}
+
+const REALM_PREFIX: &str = "realm:";
+
+/// An error that happened while parsing a role or a role expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseRoleError {
+    /// The string ends in the middle of a role or an expression
+    UnexpectedEnd,
+    /// An unexpected character was found (at the given byte offset)
+    UnexpectedCharacter(char, usize),
+    /// A role has an empty name or client ID (at the given byte offset)
+    EmptyName(usize),
+}
+
+impl std::fmt::Display for ParseRoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("Unexpected end of role expression"),
+            Self::UnexpectedCharacter(c, offset) => write!(
+                f,
+                "Unexpected character in role expression ({:?} at offset {})",
+                c, offset
+            ),
+            Self::EmptyName(offset) => write!(
+                f,
+                "Role has an empty name or client ID (at offset {})",
+                offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseRoleError {}
+
+/// A character of a token, and whether it was escaped
+type TokenChar = (char, bool);
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Vec<(usize, char)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            chars: input.char_indices().collect(),
+            pos: 0,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.chars
+            .get(self.pos)
+            .map(|(offset, _)| *offset)
+            .unwrap_or(self.input.len())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).map(|(_, c)| *c)
+    }
+
+    fn unexpected(&self) -> ParseRoleError {
+        match self.peek() {
+            Some(c) => ParseRoleError::UnexpectedCharacter(c, self.offset()),
+            None => ParseRoleError::UnexpectedEnd,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseRoleError> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn end(&mut self) -> Result<(), ParseRoleError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    /// Read characters until an unescaped delimiter (comma, parenthesis or whitespace)
+    fn token(&mut self) -> Result<Vec<TokenChar>, ParseRoleError> {
+        let mut token = vec![];
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    let escaped = self.peek().ok_or(ParseRoleError::UnexpectedEnd)?;
+                    token.push((escaped, true));
+                }
+                ',' | '(' | ')' => break,
+                c if c.is_whitespace() => break,
+                c => token.push((c, false)),
+            }
+            self.pos += 1;
+        }
+        if token.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(token)
+    }
+
+    fn role(&self, token: Vec<TokenChar>, start: usize) -> Result<Role, ParseRoleError> {
+        let text = |chars: &[TokenChar]| -> Result<String, ParseRoleError> {
+            if chars.is_empty() {
+                Err(ParseRoleError::EmptyName(start))
+            } else {
+                Ok(chars.iter().map(|(c, _)| c).collect())
+            }
+        };
+
+        let has_realm_prefix = token.len() >= REALM_PREFIX.len()
+            && token
+                .iter()
+                .zip(REALM_PREFIX.chars())
+                .all(|((c, escaped), expected)| !escaped && *c == expected);
+        if has_realm_prefix {
+            return Ok(Role::Realm {
+                role: text(&token[REALM_PREFIX.len()..])?,
+            });
+        }
+
+        match token.iter().position(|(c, escaped)| !escaped && *c == '.') {
+            Some(dot) => Ok(Role::Client {
+                client: text(&token[..dot])?,
+                role: text(&token[dot + 1..])?,
+            }),
+            None => Ok(Role::Realm {
+                role: text(&token)?,
+            }),
+        }
+    }
+
+    fn expr(&mut self) -> Result<RoleExpr, ParseRoleError> {
+        self.skip_whitespace();
+        let start = self.offset();
+        let token = self.token()?;
+        let after_token = self.pos;
+        self.skip_whitespace();
+
+        let operator: String = token
+            .iter()
+            .map(|(c, escaped)| if *escaped { '\\' } else { *c })
+            .collect();
+        match (operator.as_str(), self.peek()) {
+            ("all", Some('(')) => Ok(RoleExpr::All(self.list()?)),
+            ("any", Some('(')) => Ok(RoleExpr::Any(self.list()?)),
+            ("not", Some('(')) => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(RoleExpr::not(expr))
+            }
+            _ => {
+                self.pos = after_token;
+                Ok(RoleExpr::Role(self.role(token, start)?))
+            }
+        }
+    }
+
+    /// Parse a parenthesized list of comma-separated expressions
+    fn list(&mut self) -> Result<Vec<RoleExpr>, ParseRoleError> {
+        self.expect('(')?;
+        let mut exprs = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(')') {
+            self.pos += 1;
+            return Ok(exprs);
+        }
+        loop {
+            exprs.push(self.expr()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(exprs);
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+}
+
 pub fn check_roles(roles: &[Role], required_roles: &[Role]) -> Result<(), AuthError> {
+    check_role_expr(roles, &RoleExpr::from(required_roles.to_vec()))
+}
+
+pub fn check_role_expr(roles: &[Role], required: &RoleExpr) -> Result<(), AuthError> {
     debug!(
         "JWT contains roles: {}",
         &roles
@@ -19,17 +489,9 @@ pub fn check_roles(roles: &[Role], required_roles: &[Role]) -> Result<(), AuthEr
             .join(", ")
     );
 
-    let mut missing_roles = vec![];
-    for role in required_roles {
-        if !roles.contains(role) {
-            missing_roles.push(role.clone());
-        }
-    }
-
-    if missing_roles.is_empty() {
-        Ok(())
-    } else {
-        Err(AuthError::MissingRoles(missing_roles))
+    match required.unsatisfied(roles) {
+        None => Ok(()),
+        Some(unsatisfied) => Err(AuthError::MissingRoles(unsatisfied)),
     }
 }
 
@@ -37,7 +499,7 @@ pub fn extract_roles(
     realm_access: &Option<Access>,
     resource_access: &Option<HashMap<String, Access>>,
 ) -> Vec<Role> {
-    let mut roles = realm_access
+    let mut roles: Vec<Role> = realm_access
         .clone()
         .map(|ra| {
             ra.roles
@@ -47,9 +509,9 @@ pub fn extract_roles(
                 })
                 .collect()
         })
-        .unwrap_or_else(Vec::new);
+        .unwrap_or_default();
 
-    let mut client_roles = resource_access
+    let mut client_roles: Vec<Role> = resource_access
         .clone()
         .map(|ra| {
             ra.iter()
@@ -64,15 +526,129 @@ pub fn extract_roles(
                 })
                 .collect()
         })
-        .unwrap_or_else(Vec::new);
+        .unwrap_or_default();
 
     roles.append(&mut client_roles);
     roles
 }
 
-pub trait Roles {
-    /// Extract Keycloak roles
-    fn roles(&self) -> Vec<Role>;
+/// Something that can extract roles from JWT claims, in a custom way
+///
+/// An extractor can be defined using a closure:
+///
+/// ```
+/// use actix_web_middleware_keycloak_auth::{Role, RoleSource};
+/// use serde_json::Value;
+/// use std::sync::Arc;
+///
+/// // Roles are in a comma-separated string
+/// let source = RoleSource::Custom(Arc::new(|claims: &Value| {
+///     Ok(claims["roles"]
+///         .as_str()
+///         .unwrap_or_default()
+///         .split(',')
+///         .filter(|role| !role.is_empty())
+///         .map(|role| Role::Realm { role: role.to_owned() })
+///         .collect())
+/// }));
+/// ```
+pub trait RoleExtractor: Send + Sync {
+    /// Extract the roles from JWT claims, or explain why they cannot be extracted
+    fn extract(&self, claims: &Value) -> Result<Vec<Role>, String>;
+}
+
+/// A role extractor can be defined using a closure
+impl<F> RoleExtractor for F
+where
+    F: Fn(&Value) -> Result<Vec<Role>, String> + Send + Sync,
+{
+    fn extract(&self, claims: &Value) -> Result<Vec<Role>, String> {
+        self(claims)
+    }
+}
+
+/// A place of JWT claims where roles can be found
+///
+/// Places are [JSON pointers](https://www.rfc-editor.org/rfc/rfc6901) (for example `/realm_access/roles`, or `/https:~1~1example.com~1roles` for a `https://example.com/roles` claim).
+/// When a place is absent from the claims, no role is extracted from it.
+#[derive(Clone)]
+pub enum RoleSource {
+    /// An array of realm role names (`/realm_access/roles` in Keycloak JWT)
+    Realm(String),
+    /// An object whose keys are client IDs and values contain an array of client role names in a `roles` field (`/resource_access` in Keycloak JWT)
+    Clients(String),
+    /// An array of role names of a given client (client ID, place)
+    Client(String, String),
+    /// A custom extractor
+    Custom(Arc<dyn RoleExtractor>),
+}
+
+impl RoleSource {
+    /// The places where Keycloak puts roles by default (realm roles, then client roles)
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::Realm("/realm_access/roles".to_owned()),
+            Self::Clients("/resource_access".to_owned()),
+        ]
+    }
+
+    /// Extract roles from JWT claims
+    pub(crate) fn extract(&self, claims: &Value) -> Result<Vec<Role>, AuthError> {
+        let parse_error = |e: serde_json::Error| AuthError::RoleParsingError(e.to_string());
+        let value = |pointer: &str| {
+            claims
+                .pointer(pointer)
+                .filter(|value| !value.is_null())
+                .cloned()
+        };
+
+        match self {
+            Self::Realm(pointer) => Ok(value(pointer)
+                .map(from_value::<Vec<String>>)
+                .transpose()
+                .map_err(parse_error)?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|role| Role::Realm { role })
+                .collect()),
+            Self::Clients(pointer) => {
+                let clients = value(pointer)
+                    .map(from_value::<HashMap<String, Access>>)
+                    .transpose()
+                    .map_err(parse_error)?;
+                Ok(extract_roles(&None, &clients))
+            }
+            Self::Client(client, pointer) => Ok(value(pointer)
+                .map(from_value::<Vec<String>>)
+                .transpose()
+                .map_err(parse_error)?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|role| Role::Client {
+                    client: client.to_owned(),
+                    role,
+                })
+                .collect()),
+            Self::Custom(extractor) => extractor
+                .extract(claims)
+                .map_err(AuthError::RoleParsingError),
+        }
+    }
+}
+
+impl std::fmt::Debug for RoleSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Realm(pointer) => f.debug_tuple("Realm").field(pointer).finish(),
+            Self::Clients(pointer) => f.debug_tuple("Clients").field(pointer).finish(),
+            Self::Client(client, pointer) => f
+                .debug_tuple("Client")
+                .field(client)
+                .field(pointer)
+                .finish(),
+            Self::Custom(_) => f.debug_tuple("Custom").finish(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -120,14 +696,17 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err(),
-            AuthError::MissingRoles(vec![
-                Role::Realm {
-                    role: "test1".to_owned()
-                },
-                Role::Realm {
-                    role: "test2".to_owned()
-                }
-            ])
+            AuthError::MissingRoles(
+                vec![
+                    Role::Realm {
+                        role: "test1".to_owned()
+                    },
+                    Role::Realm {
+                        role: "test2".to_owned()
+                    }
+                ]
+                .into()
+            )
         );
     }
 
@@ -152,9 +731,12 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err(),
-            AuthError::MissingRoles(vec![Role::Realm {
-                role: "test1".to_owned()
-            }])
+            AuthError::MissingRoles(
+                vec![Role::Realm {
+                    role: "test1".to_owned()
+                }]
+                .into()
+            )
         );
     }
 
@@ -281,4 +863,328 @@ mod tests {
 
         assert!(check_roles(roles, required_roles).is_ok());
     }
+
+    #[test]
+    fn role_expressions() {
+        let roles = &extract_roles(
+            &Some(Access {
+                roles: vec!["admin".to_owned(), "banned".to_owned()],
+            }),
+            &Some(HashMap::from_iter(vec![(
+                "backoffice".to_owned(),
+                Access {
+                    roles: vec!["readonly".to_owned()],
+                },
+            )])),
+        );
+
+        let any = RoleExpr::any(vec![
+            RoleExpr::realm("superadmin"),
+            RoleExpr::client("backoffice", "readonly"),
+        ]);
+        assert!(check_role_expr(roles, &any).is_ok());
+
+        let none = RoleExpr::any(vec![
+            RoleExpr::realm("superadmin"),
+            RoleExpr::client("backoffice", "export"),
+        ]);
+        assert_eq!(
+            check_role_expr(roles, &none),
+            Err(AuthError::MissingRoles(none.clone()))
+        );
+
+        assert!(check_role_expr(roles, &RoleExpr::not(RoleExpr::realm("guest"))).is_ok());
+        assert_eq!(
+            check_role_expr(roles, &RoleExpr::not(RoleExpr::realm("banned"))),
+            Err(AuthError::MissingRoles(RoleExpr::not(RoleExpr::realm(
+                "banned"
+            ))))
+        );
+
+        assert!(RoleExpr::all(vec![]).is_satisfied_by(roles));
+        assert!(!RoleExpr::any(vec![]).is_satisfied_by(roles));
+    }
+
+    #[test]
+    fn unsatisfied_branch() {
+        let roles = &extract_roles(
+            &Some(Access {
+                roles: vec!["admin".to_owned()],
+            }),
+            &None,
+        );
+        let expr = RoleExpr::all(vec![
+            RoleExpr::realm("admin"),
+            RoleExpr::any(vec![RoleExpr::realm("a"), RoleExpr::realm("b")]),
+            RoleExpr::all(vec![RoleExpr::realm("admin"), RoleExpr::realm("c")]),
+        ]);
+
+        let unsatisfied = expr.unsatisfied(roles).unwrap();
+        assert_eq!(
+            unsatisfied,
+            RoleExpr::all(vec![
+                RoleExpr::any(vec![RoleExpr::realm("a"), RoleExpr::realm("b")]),
+                RoleExpr::all(vec![RoleExpr::realm("c")]),
+            ])
+        );
+        assert_eq!(unsatisfied.to_string(), "all(any(a, b), all(c))");
+    }
+
+    #[test]
+    fn parse_roles() {
+        let realm = |role: &str| Role::Realm {
+            role: role.to_owned(),
+        };
+        let client = |client: &str, role: &str| Role::Client {
+            client: client.to_owned(),
+            role: role.to_owned(),
+        };
+
+        assert_eq!("admin".parse(), Ok(realm("admin")));
+        assert_eq!("realm:admin".parse(), Ok(realm("admin")));
+        assert_eq!("realm:my.role".parse(), Ok(realm("my.role")));
+        assert_eq!(
+            " backoffice.readonly ".parse(),
+            Ok(client("backoffice", "readonly"))
+        );
+        assert_eq!(
+            r"my\.app.readonly".parse(),
+            Ok(client("my.app", "readonly"))
+        );
+        assert_eq!("my.app.readonly".parse(), Ok(client("my", "app.readonly")));
+        assert_eq!(r"realm\:admin".parse(), Ok(realm("realm:admin")));
+        assert_eq!("".parse::<Role>(), Err(ParseRoleError::UnexpectedEnd));
+        assert_eq!(
+            r"admin\".parse::<Role>(),
+            Err(ParseRoleError::UnexpectedEnd)
+        );
+        assert_eq!(
+            ".readonly".parse::<Role>(),
+            Err(ParseRoleError::EmptyName(0))
+        );
+        assert_eq!("realm:".parse::<Role>(), Err(ParseRoleError::EmptyName(0)));
+        assert_eq!(
+            "my role".parse::<Role>(),
+            Err(ParseRoleError::UnexpectedCharacter('r', 3))
+        );
+        assert_eq!(
+            "all(admin)".parse::<Role>(),
+            Err(ParseRoleError::UnexpectedCharacter('(', 3))
+        );
+
+        assert_eq!(
+            client("my.app", "readonly").to_string(),
+            r"my\.app.readonly"
+        );
+        assert_eq!(realm("my role").to_string(), r"my\ role");
+        assert_eq!(realm("realm:admin").to_string(), r"realm\:admin");
+    }
+
+    #[test]
+    fn parse_role_expressions() {
+        assert_eq!(
+            "all(any(admin, my\\.app.readonly), not( banned ))".parse(),
+            Ok(RoleExpr::all(vec![
+                RoleExpr::any(vec![
+                    RoleExpr::realm("admin"),
+                    RoleExpr::client("my.app", "readonly"),
+                ]),
+                RoleExpr::not(RoleExpr::realm("banned")),
+            ]))
+        );
+        assert_eq!("all()".parse(), Ok(RoleExpr::all(vec![])));
+        assert_eq!("all".parse(), Ok(RoleExpr::realm("all")));
+        assert_eq!(
+            "all(a,".parse::<RoleExpr>(),
+            Err(ParseRoleError::UnexpectedEnd)
+        );
+        assert_eq!(
+            "not(a, b)".parse::<RoleExpr>(),
+            Err(ParseRoleError::UnexpectedCharacter(',', 5))
+        );
+        assert_eq!(
+            "any(a) b".parse::<RoleExpr>(),
+            Err(ParseRoleError::UnexpectedCharacter('b', 7))
+        );
+    }
+
+    #[test]
+    fn serde_string_form() {
+        let roles: Vec<Role> = serde_json::from_str(
+            r#"["admin", "my\\.app.readonly", {"type": "Realm", "role": "user"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            roles,
+            vec![
+                Role::Realm {
+                    role: "admin".to_owned()
+                },
+                Role::Client {
+                    client: "my.app".to_owned(),
+                    role: "readonly".to_owned()
+                },
+                Role::Realm {
+                    role: "user".to_owned()
+                },
+            ]
+        );
+
+        let expr: RoleExpr = serde_json::from_str(r#""any(admin, not(user))""#).unwrap();
+        assert_eq!(
+            expr,
+            RoleExpr::any(vec![
+                RoleExpr::realm("admin"),
+                RoleExpr::not(RoleExpr::realm("user"))
+            ])
+        );
+        assert_eq!(
+            serde_json::to_string(&expr).unwrap(),
+            r#""any(admin, not(user))""#
+        );
+        assert!(serde_json::from_str::<RoleExpr>(r#""any(admin""#).is_err());
+    }
+
+    /// A small deterministic pseudo-random generator (xorshift), to generate test cases
+    struct Generator(u64);
+
+    impl Generator {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+
+        fn name(&mut self) -> String {
+            const PARTS: &[&str] = &[
+                "a", "Z", "9", "-", "_", ".", ":", "\\", ",", "(", ")", " ", "\t", "é", "realm:",
+                "realm", "all", "any", "not", "my.app",
+            ];
+            (0..1 + self.next(4))
+                .map(|_| PARTS[self.next(PARTS.len())])
+                .collect()
+        }
+
+        fn role(&mut self) -> Role {
+            if self.next(2) == 0 {
+                Role::Realm { role: self.name() }
+            } else {
+                Role::Client {
+                    client: self.name(),
+                    role: self.name(),
+                }
+            }
+        }
+
+        fn expr(&mut self, depth: usize) -> RoleExpr {
+            match if depth == 0 { 0 } else { self.next(4) } {
+                0 => RoleExpr::Role(self.role()),
+                1 => RoleExpr::all((0..self.next(4)).map(|_| self.expr(depth - 1)).collect()),
+                2 => RoleExpr::any((0..self.next(4)).map(|_| self.expr(depth - 1)).collect()),
+                _ => RoleExpr::not(self.expr(depth - 1)),
+            }
+        }
+    }
+
+    #[test]
+    fn role_display_round_trip() {
+        let mut generator = Generator(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let role = generator.role();
+            assert_eq!(role.to_string().parse(), Ok(role.clone()), "{}", role);
+
+            let json = serde_json::to_string(&role.to_string()).unwrap();
+            assert_eq!(serde_json::from_str::<Role>(&json).unwrap(), role);
+        }
+    }
+
+    #[test]
+    fn role_expression_display_round_trip() {
+        let mut generator = Generator(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..1000 {
+            let expr = generator.expr(4);
+            assert_eq!(expr.to_string().parse(), Ok(expr.clone()), "{}", expr);
+
+            let json = serde_json::to_string(&expr).unwrap();
+            assert_eq!(serde_json::from_str::<RoleExpr>(&json).unwrap(), expr);
+        }
+    }
+
+    #[test]
+    fn role_requirement_macro() {
+        crate::role_requirement!(Editor = "all(admin, backoffice.write)");
+
+        let expected = RoleExpr::all(vec![
+            RoleExpr::realm("admin"),
+            RoleExpr::client("backoffice", "write"),
+        ]);
+        assert_eq!(Editor::role_requirement(), expected);
+        assert_eq!(Editor::role_requirement(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid role expression: all(admin")]
+    fn invalid_role_requirement_macro() {
+        crate::role_requirement!(Invalid = "all(admin");
+
+        Invalid::role_requirement();
+    }
+
+    #[test]
+    fn role_sources() {
+        let claims = serde_json::json!({
+            "realm_access": { "roles": ["admin"] },
+            "resource_access": { "client1": { "roles": ["role1"] } },
+            "https://example.com/roles": ["custom"],
+            "app": { "roles": ["reader"] },
+            "invalid": "admin",
+        });
+        let extract = |sources: Vec<RoleSource>| -> Result<Vec<Role>, AuthError> {
+            let mut roles = vec![];
+            for source in sources {
+                roles.extend(source.extract(&claims)?);
+            }
+            Ok(roles)
+        };
+
+        assert_eq!(
+            extract(RoleSource::defaults()),
+            Ok(vec![
+                Role::Realm {
+                    role: "admin".to_owned()
+                },
+                Role::Client {
+                    client: "client1".to_owned(),
+                    role: "role1".to_owned()
+                },
+            ])
+        );
+        assert_eq!(
+            extract(vec![
+                RoleSource::Realm("/https:~1~1example.com~1roles".to_owned()),
+                RoleSource::Client("app".to_owned(), "/app/roles".to_owned()),
+                RoleSource::Realm("/missing".to_owned()),
+            ]),
+            Ok(vec![
+                Role::Realm {
+                    role: "custom".to_owned()
+                },
+                Role::Client {
+                    client: "app".to_owned(),
+                    role: "reader".to_owned()
+                },
+            ])
+        );
+        assert!(matches!(
+            extract(vec![RoleSource::Realm("/invalid".to_owned())]),
+            Err(AuthError::RoleParsingError(_))
+        ));
+        assert_eq!(
+            extract(vec![RoleSource::Custom(Arc::new(|_: &Value| Err(
+                "no roles".to_owned()
+            )))]),
+            Err(AuthError::RoleParsingError("no roles".to_owned()))
+        );
+    }
 }
@@ -0,0 +1,70 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use log::debug;
+use serde_json::Value;
+
+use super::errors::AuthError;
+
+/// Parse the value of a `scope` claim: space-separated OAuth scopes, or an array of scopes (a missing or null claim means that there are no scopes)
+pub fn parse_scopes(scope: Option<&Value>) -> Result<Vec<String>, String> {
+    match scope {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(Value::String(scope)) => Ok(scope.split_whitespace().map(str::to_owned).collect()),
+        Some(Value::Array(scopes)) => scopes
+            .iter()
+            .map(|scope| {
+                scope
+                    .as_str()
+                    .map(str::to_owned)
+                    .ok_or_else(|| format!("invalid scope: {}", scope))
+            })
+            .collect(),
+        Some(scope) => Err(format!("invalid scope claim: {}", scope)),
+    }
+}
+
+pub fn check_scopes(scopes: &[String], required_scopes: &[String]) -> Result<(), AuthError> {
+    debug!("JWT contains scopes: {}", scopes.join(" "));
+
+    let missing_scopes: Vec<String> = required_scopes
+        .iter()
+        .filter(|scope| !scopes.contains(scope))
+        .cloned()
+        .collect();
+
+    if missing_scopes.is_empty() {
+        Ok(())
+    } else {
+        Err(AuthError::MissingScopes(missing_scopes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scopes() {
+        let scopes = parse_scopes(Some(&json!("openid  profile orders:read"))).unwrap();
+        assert_eq!(scopes, vec!["openid", "profile", "orders:read"]);
+        assert_eq!(
+            parse_scopes(Some(&json!(["openid", "orders:read"]))),
+            Ok(vec!["openid".to_owned(), "orders:read".to_owned()])
+        );
+        assert_eq!(parse_scopes(None), Ok(vec![]));
+        assert_eq!(parse_scopes(Some(&Value::Null)), Ok(vec![]));
+        assert!(parse_scopes(Some(&json!(42))).is_err());
+        assert!(parse_scopes(Some(&json!(["openid", 42]))).is_err());
+
+        assert!(check_scopes(&scopes, &[]).is_ok());
+        assert!(check_scopes(&scopes, &["orders:read".to_owned()]).is_ok());
+        assert_eq!(
+            check_scopes(&scopes, &["orders:write".to_owned(), "profile".to_owned()]),
+            Err(AuthError::MissingScopes(vec!["orders:write".to_owned()]))
+        );
+    }
+}
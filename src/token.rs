@@ -0,0 +1,238 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use actix_web::dev::ServiceRequest;
+use actix_web::web::Query;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::errors::AuthError;
+
+/// Something that can extract a JWT from a request, in a custom way
+///
+/// An extractor can be defined using a closure:
+///
+/// ```
+/// use actix_web::dev::ServiceRequest;
+/// use actix_web_middleware_keycloak_auth::TokenSource;
+/// use std::sync::Arc;
+///
+/// let source = TokenSource::Custom(
+///     "X-Access-Token header".to_owned(),
+///     Arc::new(|req: &ServiceRequest| {
+///         req.headers()
+///             .get("X-Access-Token")
+///             .and_then(|value| value.to_str().ok())
+///             .map(str::to_owned)
+///     }),
+/// );
+/// ```
+pub trait TokenExtractor: Send + Sync {
+    /// Extract the JWT from the request (if any)
+    fn extract(&self, req: &ServiceRequest) -> Option<String>;
+}
+
+/// A token extractor can be defined using a closure
+impl<F> TokenExtractor for F
+where
+    F: Fn(&ServiceRequest) -> Option<String> + Send + Sync,
+{
+    fn extract(&self, req: &ServiceRequest) -> Option<String> {
+        self(req)
+    }
+}
+
+/// A place of requests where a JWT can be found
+#[derive(Clone)]
+pub enum TokenSource {
+    /// A header containing `Bearer [JWT]` (usually `Authorization`)
+    Header(String),
+    /// A cookie containing the JWT
+    Cookie(String),
+    /// A query parameter containing the JWT (for example `access_token`)
+    Query(String),
+    /// A custom extractor, with a name that describes it
+    Custom(String, Arc<dyn TokenExtractor>),
+}
+
+impl TokenSource {
+    /// The `Authorization` header (the default source)
+    pub fn authorization_header() -> Self {
+        Self::Header("Authorization".to_owned())
+    }
+
+    /// Try to extract a JWT from a request
+    ///
+    /// Returns `Ok(None)` if the source is absent from the request.
+    pub(crate) fn extract(&self, req: &ServiceRequest) -> Result<Option<String>, AuthError> {
+        match self {
+            Self::Header(name) => {
+                let mut values = req.headers().get_all(name);
+                let value = match values.next() {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                if values.next().is_some() {
+                    // It is not possible to know which one should be used
+                    return Err(AuthError::InvalidAuthorizationHeader);
+                }
+                let value = value
+                    .to_str()
+                    .map_err(|_| AuthError::InvalidAuthorizationHeader)?;
+                parse_bearer(value).map(Some)
+            }
+            Self::Cookie(name) => Ok(req
+                .headers()
+                .get_all("Cookie")
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, value)| value.trim_matches('"').to_owned())),
+            Self::Query(name) => Ok(Query::<HashMap<String, String>>::from_query(
+                req.query_string(),
+            )
+            .ok()
+            .and_then(|query| query.into_inner().remove(name))),
+            Self::Custom(_, extractor) => Ok(extractor.extract(req)),
+        }
+    }
+}
+
+/// Extract the token from a `Bearer [token]` credentials string (see [RFC 6750](https://www.rfc-editor.org/rfc/rfc6750#section-2.1))
+///
+/// The scheme is case-insensitive, and must be followed by a single space and a token made of allowed characters.
+fn parse_bearer(value: &str) -> Result<String, AuthError> {
+    let (scheme, token) = value
+        .split_once(' ')
+        .ok_or(AuthError::InvalidAuthorizationHeader)?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return Err(AuthError::WrongAuthorizationScheme(scheme.to_owned()));
+    }
+
+    let padding_start = token.trim_end_matches('=').len();
+    let is_b64token = padding_start > 0
+        && token[..padding_start]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~+/".contains(c));
+    if !is_b64token {
+        return Err(AuthError::InvalidAuthorizationHeader);
+    }
+
+    Ok(token.to_owned())
+}
+
+impl std::fmt::Debug for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Header(name) => f.debug_tuple("Header").field(name).finish(),
+            Self::Cookie(name) => f.debug_tuple("Cookie").field(name).finish(),
+            Self::Query(name) => f.debug_tuple("Query").field(name).finish(),
+            Self::Custom(name, _) => f.debug_tuple("Custom").field(name).finish(),
+        }
+    }
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Header(name) => write!(f, "{} header", name),
+            Self::Cookie(name) => write!(f, "{} cookie", name),
+            Self::Query(name) => write!(f, "{} query parameter", name),
+            Self::Custom(name, _) => f.write_str(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn parse_bearer_scheme() {
+        assert_eq!(
+            parse_bearer("Bearer abc.DEF-_~+/="),
+            Ok("abc.DEF-_~+/=".to_owned())
+        );
+        assert_eq!(parse_bearer("bearer abc"), Ok("abc".to_owned()));
+        assert_eq!(parse_bearer("BEARER abc"), Ok("abc".to_owned()));
+        assert_eq!(
+            parse_bearer("Basic dXNlcjpwYXNz"),
+            Err(AuthError::WrongAuthorizationScheme("Basic".to_owned()))
+        );
+        assert_eq!(
+            parse_bearer("abc.def.ghi"),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer"),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer "),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer  abc"),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer abc def"),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer a=bc"),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+        assert_eq!(
+            parse_bearer("Bearer ==="),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+    }
+
+    #[test]
+    fn extract_from_multiple_headers() {
+        let source = TokenSource::authorization_header();
+
+        let req = TestRequest::default()
+            .append_header(("Authorization", "Bearer abc"))
+            .append_header(("Authorization", "Bearer def"))
+            .to_srv_request();
+        assert_eq!(
+            source.extract(&req),
+            Err(AuthError::InvalidAuthorizationHeader)
+        );
+    }
+
+    #[test]
+    fn extract_from_cookie() {
+        let source = TokenSource::Cookie("access_token".to_owned());
+
+        let req = TestRequest::default()
+            .insert_header(("Cookie", "session=abc; access_token=jwt; other=def"))
+            .to_srv_request();
+        assert_eq!(source.extract(&req), Ok(Some("jwt".to_owned())));
+
+        let req = TestRequest::default()
+            .insert_header(("Cookie", "session=abc; my_access_token=jwt"))
+            .to_srv_request();
+        assert_eq!(source.extract(&req), Ok(None));
+
+        let req = TestRequest::default().to_srv_request();
+        assert_eq!(source.extract(&req), Ok(None));
+    }
+
+    #[test]
+    fn extract_from_query() {
+        let source = TokenSource::Query("access_token".to_owned());
+
+        let req = TestRequest::with_uri("/ws?room=1&access_token=jwt").to_srv_request();
+        assert_eq!(source.extract(&req), Ok(Some("jwt".to_owned())));
+
+        let req = TestRequest::with_uri("/ws?room=1").to_srv_request();
+        assert_eq!(source.extract(&req), Ok(None));
+    }
+}
@@ -0,0 +1,60 @@
+// actix-web-middleware-keycloak-auth
+//
+// Copyright: 2020, David Sferruzza
+// License: MIT
+
+use actix_web::dev::ServiceRequest;
+use futures_util::future::LocalBoxFuture;
+use serde_json::Value;
+use std::future::Future;
+
+use super::errors::AuthError;
+
+/// A custom check of the claims of a token, that can depend on the request (for example, a tenant claim that must match the `Host` header)
+///
+/// Validators are run in order, after the requirements of the middleware (roles, groups, scopes and permissions) are checked, and before the request reaches the handler.
+/// The first error that is returned is rendered like the other authentication errors: [AuthError::Custom](AuthError::Custom) describes errors with a custom code and message.
+///
+/// A validator can be defined using a closure:
+///
+/// ```
+/// use actix_web::dev::ServiceRequest;
+/// use actix_web_middleware_keycloak_auth::AuthError;
+/// use serde_json::Value;
+///
+/// let validator = |claims: &Value, req: &ServiceRequest| {
+///     let tenant = claims.get("tenant_id").and_then(Value::as_str).map(str::to_owned);
+///     let host = req.connection_info().host().to_owned();
+///     async move {
+///         match tenant {
+///             Some(tenant) if host.starts_with(&format!("{}.", tenant)) => Ok(()),
+///             _ => Err(AuthError::custom("wrong_tenant", "JWT token belongs to another tenant")),
+///         }
+///     }
+/// };
+/// # fn check<V: actix_web_middleware_keycloak_auth::ClaimValidator>(_: V) {}
+/// # check(validator);
+/// ```
+pub trait ClaimValidator: Send + Sync {
+    /// Validate the claims of a token, for a request
+    fn validate(
+        &self,
+        claims: &Value,
+        req: &ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<(), AuthError>>;
+}
+
+/// A claim validator can be defined using a closure
+impl<F, Fut> ClaimValidator for F
+where
+    F: Fn(&Value, &ServiceRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), AuthError>> + 'static,
+{
+    fn validate(
+        &self,
+        claims: &Value,
+        req: &ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<(), AuthError>> {
+        Box::pin(self(claims, req))
+    }
+}
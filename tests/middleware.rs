@@ -3,18 +3,28 @@
 // Copyright: 2020, David Sferruzza
 // License: MIT
 
+use actix_web::dev::ServiceRequest;
 use actix_web::http::StatusCode;
 use actix_web::web::Bytes;
-use actix_web::{test, web, App, HttpResponse, Responder};
+use actix_web::{test, web, App, HttpResponse, Responder, ResponseError};
 use actix_web_middleware_keycloak_auth::{
-    Access, AlwaysPassPolicy, AlwaysReturnPolicy, KeycloakAuth, KeycloakClaims, KeycloakRoles,
-    Role, StandardClaims, StandardKeycloakClaims, UnstructuredKeycloakClaims,
+    role_requirement, Access, AlwaysPassPolicy, AlwaysReturnPolicy, AuthError, ClaimValidator,
+    DetailedErrorRenderer, DiscoveryError, ErrorRenderer, FixedClock, HttpClient,
+    InMemoryValidationCache, Introspection, KeyError, KeySet, KeycloakAuth, KeycloakClaims,
+    KeycloakGroups, KeycloakPermissions, KeycloakRoles, KeycloakScopes, PermissionCheck,
+    PermissionRequirement, PlainTextErrorRenderer, RemoteKeySet, RemoteKeySetConfig, RequireRoles,
+    ResponseFormat, RevocationList, Role, RoleCheck, RoleExpr, RoleGuard, RoleMapping, RoleSource,
+    StandardClaims, StandardKeycloakClaims, TokenSource, UnstructuredKeycloakClaims,
 };
+use chrono::{TimeZone, Utc};
 use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header};
 use serde::Deserialize;
 use serde_json::{from_slice, json, to_string, to_value, Value};
 use std::collections::HashMap;
 use std::iter::FromIterator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use uuid::Uuid;
 
 const KEYCLOAK_PK: &str = "-----BEGIN PUBLIC KEY-----
@@ -84,6 +94,40 @@ DnJHTnzgW7rQsvhRZ0Pvy5flAv8sLG8MUdnEcWcB+lTkoiTE5BZBKyS4TCMQpYMj
 ouKbR7feuMF/qWwW/G6Q/6Q=
 -----END PRIVATE KEY-----";
 
+const KEYCLOAK_JWKS: &str = r#"{
+    "keys": [
+        {
+            "kid": "fake-key",
+            "kty": "RSA",
+            "alg": "RS256",
+            "use": "sig",
+            "n": "uzixe74ftbgadpc1dszyvdWUpAWkSISSkpXO7vXCN4GjVMXQ1G7EQaXKumD3COKSfzXXGH06VpfrBxEdFAZQ0IjlZr8iiMk1T1DPTycfjOihKWQegQMqVrKcLLQKGlzFLHZs3ggMO_lGdfE7Rvmj0bMjmz_fzds80j-zBKeWVJTocHatIqArVrKee2hFRWciv5En147ZB-Rx0mkTxw6Bt0qyiyoDDAg8_3fE19OokFNCrO_MiUYFfu-fOgf8yooMfc-hzp2FZNauP8LlsKQYTSAuik4KyzjrmWxRM-AfxHKtHhbPI2-dyg7qisVvwtCgNXG6YTMGYH1o_ZxCecKizw",
+            "e": "AQAB"
+        },
+        {
+            "kid": "key",
+            "kty": "RSA",
+            "alg": "RS256",
+            "use": "sig",
+            "n": "nzyis1ZjfNB0bBgKFMSvvkTtwlvBsaJq7S5wA-kzeVOVpVWwkWdVha4s38XM_pa_yr47av7-z3VTmvDRyAHcaT92whREFpLv9cj5lTeJSibyr_Mrm_YtjCZVWgaOYIhwrXwKLqPr_11inWsAkfIytvHWTxZYEcXLgAXFuUuaS3uF9gEiNQwzGTU1v0FqkqTBr4B8nW3HCN47XUu0t8Y0e-lf4s4OxQawWD79J9_5d3Ry0vbV3Am1FtGJiJvOwRsIfVChDpYStTcHTCMqtvWbV6L11BWkpzGXSW4Hv43qa-GSYOD2QU68Mb59oSk2OB-BtOLpJofmbGEGgvmwyCI9Mw",
+            "e": "AQAB"
+        }
+    ]
+}"#;
+
+const KEYCLOAK_JWKS_FAKE_KEY_ONLY: &str = r#"{
+    "keys": [
+        {
+            "kid": "fake-key",
+            "kty": "RSA",
+            "alg": "RS256",
+            "use": "sig",
+            "n": "uzixe74ftbgadpc1dszyvdWUpAWkSISSkpXO7vXCN4GjVMXQ1G7EQaXKumD3COKSfzXXGH06VpfrBxEdFAZQ0IjlZr8iiMk1T1DPTycfjOihKWQegQMqVrKcLLQKGlzFLHZs3ggMO_lGdfE7Rvmj0bMjmz_fzds80j-zBKeWVJTocHatIqArVrKee2hFRWciv5En147ZB-Rx0mkTxw6Bt0qyiyoDDAg8_3fE19OokFNCrO_MiUYFfu-fOgf8yooMfc-hzp2FZNauP8LlsKQYTSAuik4KyzjrmWxRM-AfxHKtHhbPI2-dyg7qisVvwtCgNXG6YTMGYH1o_ZxCecKizw",
+            "e": "AQAB"
+        }
+    ]
+}"#;
+
 async fn hello_world() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
@@ -112,6 +156,31 @@ async fn other_claim_failed(unstructured_claims: UnstructuredKeycloakClaims) ->
     HttpResponse::InternalServerError().body(res)
 }
 
+fn jwt_with_kid(kid: &str, claims: &StandardClaims) -> String {
+    encode(
+        &Header {
+            kid: Some(kid.to_owned()),
+            ..Header::new(Algorithm::RS256)
+        },
+        claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap()
+}
+
+/// A JWKS fetcher that serves a JWKS document that can be changed, and counts fetches
+fn stub_jwks_fetcher(
+    jwks: &Arc<Mutex<&'static str>>,
+    fetch_count: &Arc<AtomicUsize>,
+) -> impl Fn() -> futures_util::future::Ready<Result<String, KeyError>> {
+    let jwks = Arc::clone(jwks);
+    let fetch_count = Arc::clone(fetch_count);
+    move || {
+        fetch_count.fetch_add(1, Ordering::SeqCst);
+        futures_util::future::ready(Ok(jwks.lock().unwrap().to_string()))
+    }
+}
+
 fn init_logger() {
     std::env::set_var("RUST_LOG", "trace");
     let _ = env_logger::builder().is_test(true).try_init();
@@ -171,9 +240,7 @@ async fn no_bearer_token_no_debug() {
 
     let keycloak_auth = KeycloakAuth {
         detailed_responses: false,
-        passthrough_policy: AlwaysReturnPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
-        required_roles: vec![],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
     };
     let app = test::init_service(
         App::new()
@@ -216,7 +283,7 @@ async fn no_bearer_in_authorization_header() {
         .to_request();
     let resp = test::call_service(&app, req).await;
 
-    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     let body = test::read_body(resp).await;
     assert!(!body.is_empty());
 }
@@ -325,9 +392,6 @@ async fn missing_jwt_roles() {
     init_logger();
 
     let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysReturnPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
         required_roles: vec![
             Role::Realm {
                 role: "test1".to_owned(),
@@ -336,6 +400,7 @@ async fn missing_jwt_roles() {
                 role: "test2".to_owned(),
             },
         ],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
     };
     let app = test::init_service(
         App::new()
@@ -377,9 +442,6 @@ async fn valid_jwt_roles() {
     init_logger();
 
     let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysReturnPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
         required_roles: vec![
             Role::Realm {
                 role: "test1".to_owned(),
@@ -392,6 +454,7 @@ async fn valid_jwt_roles() {
                 role: "test3".to_owned(),
             },
         ],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
     };
     let app = test::init_service(
         App::new()
@@ -434,6 +497,79 @@ async fn valid_jwt_roles() {
     assert_eq!(body, Bytes::from(user_id.to_string()));
 }
 
+#[actix_web::test]
+async fn role_requirement() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        role_requirement: Some(RoleExpr::all(vec![
+            RoleExpr::any(vec![
+                RoleExpr::realm("admin"),
+                RoleExpr::client("client", "test3"),
+            ]),
+            RoleExpr::not(RoleExpr::realm("banned")),
+        ])),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let cases = [
+        (vec!["admin"], vec![], StatusCode::OK, None),
+        (vec![], vec!["test3"], StatusCode::OK, None),
+        (
+            vec!["user"],
+            vec![],
+            StatusCode::FORBIDDEN,
+            Some("JWT token is missing roles: any(admin, client.test3)"),
+        ),
+        (
+            vec!["admin", "banned"],
+            vec!["test3"],
+            StatusCode::FORBIDDEN,
+            Some("JWT token is missing roles: not(banned)"),
+        ),
+    ];
+    for (realm_roles, client_roles, status, error) in cases {
+        let claims = StandardClaims {
+            realm_access: Some(Access {
+                roles: realm_roles.into_iter().map(str::to_owned).collect(),
+            }),
+            resource_access: Some(HashMap::from_iter(vec![(
+                "client".to_owned(),
+                Access {
+                    roles: client_roles.into_iter().map(str::to_owned).collect(),
+                },
+            )])),
+            ..StandardClaims::default()
+        };
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        if let Some(error) = error {
+            let body = test::read_body(resp).await;
+            assert_eq!(body, Bytes::from(error));
+        }
+    }
+}
+
 #[actix_web::test]
 async fn roles_extractor() {
     init_logger();
@@ -500,13 +636,11 @@ async fn from_raw_claims_single_aud_as_string() {
     init_logger();
 
     let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysReturnPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
         required_roles: vec![Role::Client {
             client: "client1".to_owned(),
             role: "test1".to_owned(),
         }],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
     };
     let app = test::init_service(
         App::new()
@@ -695,12 +829,8 @@ async fn with_custom_claims() {
 async fn always_return_policy() {
     init_logger();
 
-    let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysReturnPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
-        required_roles: vec![],
-    };
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
     let app = test::init_service(
         App::new()
             .service(
@@ -722,12 +852,9 @@ async fn always_return_policy() {
 async fn always_pass_policy() {
     init_logger();
 
-    let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysPassPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
-        required_roles: vec![],
-    };
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+            .with_passthrough_policy(AlwaysPassPolicy);
     let app = test::init_service(
         App::new()
             .service(
@@ -747,12 +874,9 @@ async fn always_pass_policy() {
 
 #[actix_web::test]
 async fn compat_with_non_boxed_middleware() {
-    let keycloak_auth = KeycloakAuth {
-        detailed_responses: true,
-        passthrough_policy: AlwaysPassPolicy,
-        keycloak_oid_public_key: DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
-        required_roles: vec![],
-    };
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+            .with_passthrough_policy(AlwaysPassPolicy);
 
     let _app = actix_web::App::new()
         .wrap(keycloak_auth.clone())
@@ -772,3 +896,3285 @@ async fn compat_with_non_boxed_middleware() {
         .wrap(keycloak_auth)
         .route(actix_web::web::to(|| async { "" }));
 }
+
+#[actix_web::test]
+async fn jwks_select_key_by_kid() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(KeySet::from_jwks(KEYCLOAK_JWKS).unwrap());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+
+    for (kid, private_key) in [("key", KEYCLOAK_KEY), ("fake-key", KEYCLOAK_FAKE_KEY)] {
+        let jwt = encode(
+            &Header {
+                kid: Some(kid.to_owned()),
+                ..Header::new(Algorithm::RS256)
+            },
+            &claims,
+            &EncodingKey::from_rsa_pem(private_key.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(user_id.to_string()));
+    }
+}
+
+#[actix_web::test]
+async fn jwks_wrong_kid() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(KeySet::from_jwks(KEYCLOAK_JWKS).unwrap());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims::default();
+    let jwt = encode(
+        &Header {
+            kid: Some("fake-key".to_owned()),
+            ..Header::new(Algorithm::RS256)
+        },
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn jwks_unknown_kid() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(KeySet::from_jwks(KEYCLOAK_JWKS).unwrap());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims::default();
+    let jwt = encode(
+        &Header {
+            kid: Some("unknown-key".to_owned()),
+            ..Header::new(Algorithm::RS256)
+        },
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert!(String::from_utf8(body.to_vec())
+        .unwrap()
+        .contains("unknown-key"));
+}
+
+#[actix_web::test]
+async fn jwks_from_file() {
+    init_logger();
+
+    let jwks_path = std::env::temp_dir().join(format!("jwks-{}.json", Uuid::new_v4()));
+    std::fs::write(&jwks_path, KEYCLOAK_JWKS).unwrap();
+    let key_set = KeySet::from_jwks_file(&jwks_path).unwrap();
+    std::fs::remove_file(&jwks_path).unwrap();
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(key_set);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header {
+            kid: Some("key".to_owned()),
+            ..Header::new(Algorithm::RS256)
+        },
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert!(resp.status().is_success());
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from(user_id.to_string()));
+}
+
+#[actix_web::test]
+async fn remote_jwks_refetch_on_unknown_kid() {
+    init_logger();
+
+    let jwks = Arc::new(Mutex::new(KEYCLOAK_JWKS_FAKE_KEY_ONLY));
+    let fetch_count = Arc::new(AtomicUsize::new(0));
+    let remote_key_set = RemoteKeySet::with_config(
+        stub_jwks_fetcher(&jwks, &fetch_count),
+        RemoteKeySetConfig {
+            refresh_interval: Duration::from_secs(3600),
+            min_refetch_interval: Duration::ZERO,
+        },
+    );
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    let jwt = jwt_with_kid("key", &claims);
+
+    // Keys are fetched on the first request, but the JWKS does not contain the right key yet
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+
+    // Keycloak rotated its keys
+    *jwks.lock().unwrap() = KEYCLOAK_JWKS;
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from(user_id.to_string()));
+
+    // Known key: no fetch
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn remote_jwks_unavailable() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(RemoteKeySet::new(|| async {
+        Err::<String, _>(KeyError::Fetch("connection refused".to_owned()))
+    }));
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &StandardClaims::default())),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().get("WWW-Authenticate").is_none());
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(
+            b"Keys to verify JWT token are unavailable (Cannot fetch JWKS (connection refused))"
+        )
+    );
+}
+
+#[actix_web::test]
+async fn remote_jwks_refetch_is_rate_limited() {
+    init_logger();
+
+    let jwks = Arc::new(Mutex::new(KEYCLOAK_JWKS));
+    let fetch_count = Arc::new(AtomicUsize::new(0));
+    let remote_key_set = RemoteKeySet::with_config(
+        stub_jwks_fetcher(&jwks, &fetch_count),
+        RemoteKeySetConfig {
+            refresh_interval: Duration::from_secs(3600),
+            min_refetch_interval: Duration::from_secs(3600),
+        },
+    );
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims::default();
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &claims)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+
+    for _ in 0..3 {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", jwt_with_kid("unknown-key", &claims)),
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn remote_jwks_background_refresh() {
+    init_logger();
+
+    let jwks = Arc::new(Mutex::new(KEYCLOAK_JWKS));
+    let fetch_count = Arc::new(AtomicUsize::new(0));
+    let remote_key_set = RemoteKeySet::with_config(
+        stub_jwks_fetcher(&jwks, &fetch_count),
+        RemoteKeySetConfig {
+            refresh_interval: Duration::ZERO,
+            min_refetch_interval: Duration::ZERO,
+        },
+    );
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set.clone());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims::default();
+    let jwt = jwt_with_kid("key", &claims);
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+
+    // Keys are stale: the request is served with the current keys, and they are refreshed in background
+    *jwks.lock().unwrap() = KEYCLOAK_JWKS_FAKE_KEY_ONLY;
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        remote_key_set.key_set().unwrap().kids(),
+        vec![Some("fake-key")]
+    );
+}
+
+/// A JWKS fetcher that serves the JWKS document after a delay (the first fetch can be slower than the others), and counts fetches
+fn slow_jwks_fetcher(
+    first_delay: Duration,
+    delay: Duration,
+    fetch_count: &Arc<AtomicUsize>,
+) -> impl Fn() -> futures_util::future::LocalBoxFuture<'static, Result<String, KeyError>> {
+    let fetch_count = Arc::clone(fetch_count);
+    move || {
+        let delay = if fetch_count.fetch_add(1, Ordering::SeqCst) == 0 {
+            first_delay
+        } else {
+            delay
+        };
+        Box::pin(async move {
+            actix_web::rt::time::sleep(delay).await;
+            Ok(KEYCLOAK_JWKS.to_owned())
+        })
+    }
+}
+
+#[actix_web::test]
+async fn remote_jwks_dropped_fetch() {
+    init_logger();
+
+    let fetch_count = Arc::new(AtomicUsize::new(0));
+    let remote_key_set = RemoteKeySet::with_config(
+        slow_jwks_fetcher(Duration::from_secs(3600), Duration::ZERO, &fetch_count),
+        RemoteKeySetConfig {
+            refresh_interval: Duration::from_secs(3600),
+            min_refetch_interval: Duration::ZERO,
+        },
+    );
+
+    // The first fetch is abandoned before it ends
+    let result =
+        actix_web::rt::time::timeout(Duration::from_millis(10), remote_key_set.refresh()).await;
+    assert!(result.is_err());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+    assert!(remote_key_set.key_set().is_none());
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set.clone());
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    // An unknown key ID still triggers a fetch
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &StandardClaims::default())),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn remote_jwks_concurrent_requests_on_cold_start() {
+    init_logger();
+
+    let fetch_count = Arc::new(AtomicUsize::new(0));
+    let remote_key_set = RemoteKeySet::with_config(
+        slow_jwks_fetcher(
+            Duration::from_millis(50),
+            Duration::from_millis(50),
+            &fetch_count,
+        ),
+        RemoteKeySetConfig {
+            refresh_interval: Duration::from_secs(3600),
+            min_refetch_interval: Duration::from_secs(3600),
+        },
+    );
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set);
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    let request = || {
+        test::TestRequest::with_uri("/private")
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", jwt_with_kid("key", &StandardClaims::default())),
+            ))
+            .to_request()
+    };
+    // The second request waits for the fetch triggered by the first one
+    let (resp1, resp2) = futures_util::future::join(
+        test::call_service(&app, request()),
+        test::call_service(&app, request()),
+    )
+    .await;
+    assert!(resp1.status().is_success());
+    assert!(resp2.status().is_success());
+    assert_eq!(fetch_count.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(feature = "http_client")]
+#[actix_web::test]
+async fn remote_jwks_from_stub_server() {
+    init_logger();
+
+    let server = actix_web::HttpServer::new(|| {
+        App::new().route(
+            "/realms/test/protocol/openid-connect/certs",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(KEYCLOAK_JWKS)
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let server_handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let remote_key_set = RemoteKeySet::from_url(format!(
+        "http://{}/realms/test/protocol/openid-connect/certs",
+        address
+    ));
+    remote_key_set.refresh().await.unwrap();
+    assert_eq!(
+        remote_key_set.key_set().unwrap().kids(),
+        vec![Some("fake-key"), Some("key")]
+    );
+
+    let keycloak_auth = KeycloakAuth::default_with_keys(remote_key_set);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &claims)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from(user_id.to_string()));
+
+    server_handle.stop(false).await;
+}
+
+#[cfg(feature = "http_client")]
+#[actix_web::test]
+async fn discovery_from_stub_server() {
+    init_logger();
+
+    let server = actix_web::HttpServer::new(|| {
+        App::new()
+            .route(
+                "/realms/test/.well-known/openid-configuration",
+                web::get().to(|req: actix_web::HttpRequest| async move {
+                    let issuer = format!("http://{}/realms/test", req.connection_info().host());
+                    HttpResponse::Ok().json(json!({
+                        "issuer": &issuer,
+                        "jwks_uri": format!("{}/protocol/openid-connect/certs", &issuer),
+                        "id_token_signing_alg_values_supported": ["RS256"],
+                    }))
+                }),
+            )
+            .route(
+                "/realms/test/protocol/openid-connect/certs",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("application/json")
+                        .body(KEYCLOAK_JWKS)
+                }),
+            )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let server_handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let issuer = format!("http://{}/realms/test", address);
+    let keycloak_auth = KeycloakAuth::discover(&issuer).await.unwrap();
+    assert_eq!(keycloak_auth.issuers, vec![issuer.clone()]);
+    assert_eq!(keycloak_auth.allowed_algorithms, vec![Algorithm::RS256]);
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        iss: issuer,
+        ..StandardClaims::default()
+    };
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &claims)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from(user_id.to_string()));
+
+    server_handle.stop(false).await;
+}
+
+fn stub_discovery_client(
+    issuer: &'static str,
+) -> impl Fn(&str) -> futures_util::future::Ready<Result<String, String>> + Send + Sync {
+    move |url: &str| {
+        futures_util::future::ready(
+            if url == format!("{}/.well-known/openid-configuration", issuer) {
+                Ok(json!({
+                    "issuer": issuer,
+                    "jwks_uri": format!("{}/protocol/openid-connect/certs", issuer),
+                })
+                .to_string())
+            } else if url == format!("{}/protocol/openid-connect/certs", issuer) {
+                Ok(KEYCLOAK_JWKS.to_owned())
+            } else {
+                Err(format!("Unexpected URL: {}", url))
+            },
+        )
+    }
+}
+
+#[actix_web::test]
+async fn discovery_invalid_issuer() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::discover_with_client(
+        "https://sso.example.com/realms/test/",
+        stub_discovery_client("https://sso.example.com/realms/test"),
+    )
+    .await
+    .unwrap();
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims {
+        iss: "https://sso.example.com/realms/test".to_owned(),
+        ..StandardClaims::default()
+    };
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &claims)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let claims = StandardClaims {
+        iss: "https://sso.example.com/realms/other".to_owned(),
+        ..StandardClaims::default()
+    };
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", jwt_with_kid("key", &claims)),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(
+            b"JWT token has an invalid issuer (https://sso.example.com/realms/other)"
+        )
+    );
+}
+
+#[actix_web::test]
+async fn discovery_issuer_mismatch() {
+    init_logger();
+
+    let result = KeycloakAuth::discover_with_client(
+        "https://sso.example.com/realms/other",
+        |_: &str| async {
+            Ok::<_, String>(
+                json!({
+                    "issuer": "https://sso.example.com/realms/test",
+                    "jwks_uri": "https://sso.example.com/realms/test/protocol/openid-connect/certs",
+                })
+                .to_string(),
+            )
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(DiscoveryError::IssuerMismatch(issuer)) if issuer == "https://sso.example.com/realms/test"
+    ));
+}
+
+#[actix_web::test]
+async fn hs256_signed_with_public_key() {
+    init_logger();
+
+    let claims = StandardClaims::default();
+    let jwt = encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(KEYCLOAK_PK.as_bytes()),
+    )
+    .unwrap();
+    let jwt_with_kid = encode(
+        &Header {
+            kid: Some("key".to_owned()),
+            ..Header::new(Algorithm::HS256)
+        },
+        &claims,
+        &EncodingKey::from_secret(KEYCLOAK_PK.as_bytes()),
+    )
+    .unwrap();
+
+    for (keycloak_auth, jwt) in [
+        (
+            KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            ),
+            &jwt,
+        ),
+        (
+            KeycloakAuth {
+                allowed_algorithms: vec![Algorithm::RS256, Algorithm::HS256],
+                ..KeycloakAuth::default_with_pk(
+                    DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+                )
+            },
+            &jwt,
+        ),
+        (
+            KeycloakAuth::default_with_keys(KeySet::from_jwks(KEYCLOAK_JWKS).unwrap()),
+            &jwt_with_kid,
+        ),
+    ] {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/private")
+                        .wrap(keycloak_auth)
+                        .route("", web::get().to(private)),
+                )
+                .service(web::resource("/").to(hello_world)),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body,
+            Bytes::from_static(b"JWT token is signed using a forbidden algorithm (HS256)")
+        );
+    }
+}
+
+#[actix_web::test]
+async fn algorithm_not_allowed() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        allowed_algorithms: vec![Algorithm::RS256],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims::default();
+    let jwt = encode(
+        &Header::new(Algorithm::RS512),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(b"JWT token is signed using a forbidden algorithm (RS512)")
+    );
+}
+
+#[actix_web::test]
+async fn algorithm_of_key_family_allowed_by_default() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    for alg in [Algorithm::RS512, Algorithm::PS256] {
+        let jwt = encode(
+            &Header::new(alg),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(user_id.to_string()));
+    }
+}
+
+#[actix_web::test]
+async fn issuer_and_audience() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        issuers: vec![
+            "https://sso.example.com/realms/test".to_owned(),
+            "https://sso.example.com/realms/other".to_owned(),
+        ],
+        audiences: vec!["account".to_owned(), "backoffice".to_owned()],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(hello_world)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let default = StandardClaims::default();
+    for (iss, aud, expected_status, expected_body) in [
+        (
+            json!("https://sso.example.com/realms/test"),
+            json!("backoffice"),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            json!("https://sso.example.com/realms/other"),
+            json!(["frontend", "account"]),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            json!("https://sso.example.com/realms/evil"),
+            json!("account"),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has an invalid issuer (https://sso.example.com/realms/evil)",
+        ),
+        (
+            Value::Null,
+            json!("account"),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has no issuer",
+        ),
+        (
+            json!("https://sso.example.com/realms/test"),
+            json!("frontend"),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has an invalid audience (frontend)",
+        ),
+        (
+            json!("https://sso.example.com/realms/test"),
+            json!(["frontend", "mobile"]),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has an invalid audience (frontend, mobile)",
+        ),
+        (
+            json!("https://sso.example.com/realms/test"),
+            Value::Null,
+            StatusCode::UNAUTHORIZED,
+            "JWT token has no audience",
+        ),
+    ] {
+        let claims = json!({
+            "sub": default.sub,
+            "exp": default.exp.timestamp(),
+            "iss": iss,
+            "aud": aud,
+            "iat": default.iat.timestamp(),
+            "jti": default.jti,
+            "azp": default.azp,
+        });
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), expected_status);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(expected_body));
+    }
+}
+
+#[actix_web::test]
+async fn time_claims() {
+    init_logger();
+
+    let default_config =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+    let now = Utc::now().timestamp();
+    for (keycloak_auth, exp, nbf, iat, expected_status, expected_body) in [
+        (
+            default_config.clone(),
+            now - 30,
+            Value::Null,
+            json!(now - 300),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            default_config.clone(),
+            now - 120,
+            Value::Null,
+            json!(now - 300),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has expired",
+        ),
+        (
+            KeycloakAuth {
+                leeway: Duration::from_secs(0),
+                ..default_config.clone()
+            },
+            now - 30,
+            Value::Null,
+            json!(now - 300),
+            StatusCode::UNAUTHORIZED,
+            "JWT token has expired",
+        ),
+        (
+            default_config.clone(),
+            now + 300,
+            json!(now + 30),
+            json!(now),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            default_config.clone(),
+            now + 300,
+            json!(now + 120),
+            json!(now),
+            StatusCode::UNAUTHORIZED,
+            "JWT token is not valid yet",
+        ),
+        (
+            KeycloakAuth {
+                validate_nbf: false,
+                ..default_config.clone()
+            },
+            now + 300,
+            json!(now + 120),
+            json!(now),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            KeycloakAuth {
+                max_age: Some(Duration::from_secs(600)),
+                ..default_config.clone()
+            },
+            now + 3600,
+            Value::Null,
+            json!(now - 300),
+            StatusCode::OK,
+            "Hello world!",
+        ),
+        (
+            KeycloakAuth {
+                max_age: Some(Duration::from_secs(600)),
+                ..default_config.clone()
+            },
+            now + 3600,
+            Value::Null,
+            json!(now - 3600),
+            StatusCode::UNAUTHORIZED,
+            "JWT token was issued too long ago",
+        ),
+        (
+            KeycloakAuth {
+                max_age: Some(Duration::from_secs(600)),
+                ..default_config.clone()
+            },
+            now + 3600,
+            Value::Null,
+            Value::Null,
+            StatusCode::UNAUTHORIZED,
+            "JWT token was issued too long ago",
+        ),
+    ] {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/private")
+                        .wrap(keycloak_auth)
+                        .route("", web::get().to(hello_world)),
+                )
+                .service(web::resource("/").to(hello_world)),
+        )
+        .await;
+
+        let mut claims = json!({ "sub": Uuid::new_v4(), "exp": exp });
+        if !nbf.is_null() {
+            claims["nbf"] = nbf;
+        }
+        if !iat.is_null() {
+            claims["iat"] = iat;
+        }
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), expected_status);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(expected_body));
+    }
+}
+
+/// Send a request with a JWT containing the provided claims to a middleware using the provided clock, for each given time, and check responses
+async fn check_time_claims_with_clock(
+    keycloak_auth: KeycloakAuth<AlwaysReturnPolicy>,
+    claims: Value,
+    clock: FixedClock,
+    expected: &[(i64, StatusCode, &str)],
+) {
+    let keycloak_auth = KeycloakAuth {
+        clock: Arc::new(clock.clone()),
+        ..keycloak_auth
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(hello_world)),
+        ),
+    )
+    .await;
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    for (now, expected_status, expected_body) in expected {
+        clock.set(Utc.timestamp_opt(*now, 0).unwrap());
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), *expected_status, "at {}", now);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(expected_body.to_string()), "at {}", now);
+    }
+}
+
+#[actix_web::test]
+async fn expired_jwt_with_fixed_clock() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+    let exp = 1_600_000_000;
+    let claims = json!({ "sub": Uuid::new_v4(), "exp": exp });
+    let clock = FixedClock::new(Utc::now());
+
+    check_time_claims_with_clock(
+        KeycloakAuth {
+            leeway: Duration::from_secs(0),
+            ..keycloak_auth.clone()
+        },
+        claims.clone(),
+        clock.clone(),
+        &[
+            (exp - 3600, StatusCode::OK, "Hello world!"),
+            (exp - 1, StatusCode::OK, "Hello world!"),
+            (exp, StatusCode::OK, "Hello world!"),
+            (exp + 1, StatusCode::UNAUTHORIZED, "JWT token has expired"),
+            (
+                exp + 3600,
+                StatusCode::UNAUTHORIZED,
+                "JWT token has expired",
+            ),
+        ],
+    )
+    .await;
+
+    check_time_claims_with_clock(
+        keycloak_auth,
+        claims,
+        clock,
+        &[
+            (exp + 59, StatusCode::OK, "Hello world!"),
+            (exp + 60, StatusCode::OK, "Hello world!"),
+            (exp + 61, StatusCode::UNAUTHORIZED, "JWT token has expired"),
+        ],
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn not_yet_valid_jwt_with_fixed_clock() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+    let nbf = 1_600_000_000;
+    let claims = json!({ "sub": Uuid::new_v4(), "exp": nbf + 3600, "nbf": nbf });
+    let clock = FixedClock::new(Utc::now());
+
+    check_time_claims_with_clock(
+        KeycloakAuth {
+            leeway: Duration::from_secs(0),
+            ..keycloak_auth.clone()
+        },
+        claims.clone(),
+        clock.clone(),
+        &[
+            (
+                nbf - 3600,
+                StatusCode::UNAUTHORIZED,
+                "JWT token is not valid yet",
+            ),
+            (
+                nbf - 1,
+                StatusCode::UNAUTHORIZED,
+                "JWT token is not valid yet",
+            ),
+            (nbf, StatusCode::OK, "Hello world!"),
+            (nbf + 1, StatusCode::OK, "Hello world!"),
+        ],
+    )
+    .await;
+
+    check_time_claims_with_clock(
+        keycloak_auth,
+        claims,
+        clock,
+        &[
+            (
+                nbf - 61,
+                StatusCode::UNAUTHORIZED,
+                "JWT token is not valid yet",
+            ),
+            (nbf - 60, StatusCode::OK, "Hello world!"),
+        ],
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn too_old_jwt_with_fixed_clock() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        leeway: Duration::from_secs(0),
+        max_age: Some(Duration::from_secs(600)),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let iat = 1_600_000_000;
+    let claims = json!({ "sub": Uuid::new_v4(), "exp": iat + 3600, "iat": iat });
+    let clock = FixedClock::new(Utc::now());
+
+    check_time_claims_with_clock(
+        keycloak_auth,
+        claims,
+        clock,
+        &[
+            (iat, StatusCode::OK, "Hello world!"),
+            (iat + 600, StatusCode::OK, "Hello world!"),
+            (
+                iat + 601,
+                StatusCode::UNAUTHORIZED,
+                "JWT token was issued too long ago",
+            ),
+        ],
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn problem_json_missing_roles() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        response_format: ResponseFormat::ProblemJson,
+        required_roles: vec![
+            Role::Realm {
+                role: "test1".to_owned(),
+            },
+            Role::Client {
+                client: "client1".to_owned(),
+                role: "test2".to_owned(),
+            },
+        ],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let claims = StandardClaims {
+        realm_access: Some(Access {
+            roles: vec!["test2".to_owned()],
+        }),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .insert_header(("X-Request-Id", "some-request-id"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        resp.headers().get("Content-Type").unwrap(),
+        "application/problem+json"
+    );
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Value>(&body).unwrap(),
+        json!({
+            "type": "urn:actix-web-middleware-keycloak-auth:error:missing_roles",
+            "title": "Forbidden",
+            "status": 403,
+            "code": "missing_roles",
+            "detail": "JWT token is missing roles: test1, client1.test2",
+            "missing_roles": ["test1", "client1.test2"],
+            "correlation_id": "some-request-id",
+        })
+    );
+}
+
+#[actix_web::test]
+async fn problem_json_no_bearer_token_no_debug() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        detailed_responses: false,
+        response_format: ResponseFormat::ProblemJson,
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private").to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    let problem = from_slice::<Value>(&body).unwrap();
+    let correlation_id = problem["correlation_id"].as_str().unwrap();
+    assert!(Uuid::parse_str(correlation_id).is_ok());
+    assert_eq!(
+        problem,
+        json!({
+            "type": "about:blank",
+            "title": "Unauthorized",
+            "status": 401,
+            "correlation_id": correlation_id,
+        })
+    );
+}
+
+#[actix_web::test]
+async fn www_authenticate_challenges() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        challenge_realm: Some("test".to_owned()),
+        required_roles: vec![Role::Realm {
+            role: "admin".to_owned(),
+        }],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer realm="test""#
+    );
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer test"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer realm="test", error="invalid_token", error_description="Invalid JWT token (InvalidToken)""#
+    );
+
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &StandardClaims::default(),
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer realm="test", error="insufficient_scope", error_description="JWT token is missing roles: admin""#
+    );
+}
+
+#[actix_web::test]
+async fn www_authenticate_challenges_no_debug() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        detailed_responses: false,
+        challenge_realm: Some("test".to_owned()),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer test"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer realm="test", error="invalid_token""#
+    );
+}
+
+#[actix_web::test]
+async fn malformed_authorization_header() {
+    init_logger();
+
+    let malformed_header =
+        actix_web::http::header::HeaderValue::from_bytes(b"Bearer \xff").unwrap();
+    let default_config =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+
+    for (keycloak_auth, expected_status) in [
+        (default_config.clone(), StatusCode::BAD_REQUEST),
+        (
+            KeycloakAuth {
+                unauthorized_on_malformed_header: true,
+                ..default_config.clone()
+            },
+            StatusCode::UNAUTHORIZED,
+        ),
+    ] {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/private")
+                        .wrap(keycloak_auth)
+                        .route("", web::get().to(private)),
+                )
+                .service(web::resource("/").to(hello_world)),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", malformed_header.clone()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), expected_status);
+        if expected_status == StatusCode::UNAUTHORIZED {
+            assert_eq!(
+                resp.headers().get("WWW-Authenticate").unwrap(),
+                r#"Bearer error="invalid_request", error_description="Authorization header value is invalid (expected: Bearer [JWT])""#
+            );
+        } else {
+            assert!(resp.headers().get("WWW-Authenticate").is_none());
+        }
+    }
+}
+
+#[actix_web::test]
+async fn custom_error_renderer() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        error_renderer: Some(Arc::new(|e: &AuthError, req: &ServiceRequest| {
+            HttpResponse::build(e.status_code())
+                .insert_header(("X-Error-Code", e.code()))
+                .json(json!({
+                    "error": {
+                        "code": e.code(),
+                        "message": e.to_string(),
+                        "path": req.path(),
+                    }
+                }))
+        })),
+        required_roles: vec![Role::Realm {
+            role: "admin".to_owned(),
+        }],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("X-Error-Code").unwrap(),
+        "no_authorization_header"
+    );
+    assert!(resp.headers().get("WWW-Authenticate").is_none());
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Value>(&body).unwrap(),
+        json!({
+            "error": {
+                "code": "no_authorization_header",
+                "message": "No bearer token was provided (tried: Authorization header)",
+                "path": "/private",
+            }
+        })
+    );
+
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &StandardClaims::default(),
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Value>(&body).unwrap(),
+        json!({
+            "error": {
+                "code": "missing_roles",
+                "message": "JWT token is missing roles: admin",
+                "path": "/private",
+            }
+        })
+    );
+}
+
+#[actix_web::test]
+async fn builtin_error_renderers() {
+    init_logger();
+
+    let renderers: [(Arc<dyn ErrorRenderer>, &str); 2] = [
+        (Arc::new(PlainTextErrorRenderer), "401 Unauthorized"),
+        (
+            Arc::new(DetailedErrorRenderer),
+            "No bearer token was provided (tried: Authorization header)",
+        ),
+    ];
+    for (error_renderer, expected_body) in renderers {
+        let keycloak_auth = KeycloakAuth {
+            error_renderer: Some(error_renderer),
+            ..KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            )
+        };
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/private")
+                        .wrap(keycloak_auth)
+                        .route("", web::get().to(private)),
+                )
+                .service(web::resource("/").to(hello_world)),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/private").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(expected_body));
+    }
+}
+
+#[actix_web::test]
+async fn token_sources() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        token_sources: vec![
+            TokenSource::authorization_header(),
+            TokenSource::Cookie("access_token".to_owned()),
+            TokenSource::Query("access_token".to_owned()),
+            TokenSource::Custom(
+                "X-Access-Token header".to_owned(),
+                Arc::new(|req: &ServiceRequest| {
+                    req.headers()
+                        .get("X-Access-Token")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_owned)
+                }),
+            ),
+        ],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    for req in [
+        test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt))),
+        test::TestRequest::with_uri("/private")
+            .insert_header(("Cookie", format!("session=abc; access_token={}", &jwt))),
+        test::TestRequest::with_uri(&format!("/private?access_token={}", &jwt)),
+        test::TestRequest::with_uri("/private").insert_header(("X-Access-Token", jwt.clone())),
+        // The first source that is present is used
+        test::TestRequest::with_uri("/private?access_token=invalid")
+            .insert_header(("Cookie", format!("access_token={}", &jwt))),
+        // A header that does not contain a bearer token is skipped
+        test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", "Basic x"))
+            .insert_header(("Cookie", format!("access_token={}", &jwt))),
+        test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", "Bearer"))
+            .insert_header(("Cookie", format!("access_token={}", &jwt))),
+    ] {
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert!(resp.status().is_success());
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(user_id.to_string()));
+    }
+
+    let req = test::TestRequest::with_uri("/private?other=1")
+        .insert_header(("Cookie", "session=abc"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(b"No bearer token was provided (tried: Authorization header, access_token cookie, access_token query parameter, X-Access-Token header)")
+    );
+
+    // When no source contains a token, the error of the header is returned
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Basic x"))
+        .insert_header(("Cookie", "session=abc"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(
+            b"Authorization header uses an unsupported scheme (Basic, expected: Bearer)"
+        )
+    );
+}
+
+#[actix_web::test]
+async fn malformed_authorization_header_forms() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap());
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(keycloak_auth)
+                    .route("", web::get().to(private)),
+            )
+            .service(web::resource("/").to(hello_world)),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = StandardClaims {
+        sub: user_id.to_owned(),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    for scheme in ["Bearer", "bearer", "BEARER", "bEaReR"] {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("{} {}", scheme, &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "{}", scheme);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(user_id.to_string()));
+    }
+
+    for (value, expected_status, expected_body) in [
+        (
+            format!("Basic {}", &jwt),
+            StatusCode::UNAUTHORIZED,
+            "Authorization header uses an unsupported scheme (Basic, expected: Bearer)",
+        ),
+        (
+            jwt.clone(),
+            StatusCode::BAD_REQUEST,
+            "Authorization header value is invalid (expected: Bearer [JWT])",
+        ),
+        (
+            format!("Bearer  {}", &jwt),
+            StatusCode::BAD_REQUEST,
+            "Authorization header value is invalid (expected: Bearer [JWT])",
+        ),
+        (
+            format!("Bearer {} extra", &jwt),
+            StatusCode::BAD_REQUEST,
+            "Authorization header value is invalid (expected: Bearer [JWT])",
+        ),
+        (
+            "Bearer".to_owned(),
+            StatusCode::BAD_REQUEST,
+            "Authorization header value is invalid (expected: Bearer [JWT])",
+        ),
+    ] {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", value.clone()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), expected_status, "{}", &value);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(expected_body), "{}", &value);
+    }
+
+    let req = test::TestRequest::with_uri("/private")
+        .append_header(("Authorization", format!("Bearer {}", &jwt)))
+        .append_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(b"Authorization header value is invalid (expected: Bearer [JWT])")
+    );
+}
+
+#[actix_web::test]
+async fn per_route_role_checks() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        response_format: ResponseFormat::ProblemJson,
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private))
+                .service(
+                    web::resource("/admin")
+                        .wrap(RoleCheck::new(RoleExpr::any(vec![
+                            RoleExpr::realm("admin"),
+                            RoleExpr::client("client1", "admin"),
+                        ])))
+                        .route(web::get().to(hello_world)),
+                )
+                .route(
+                    "/guarded",
+                    web::get()
+                        .guard(RoleGuard::new(vec![Role::Realm {
+                            role: "admin".to_owned(),
+                        }]))
+                        .to(hello_world),
+                ),
+        ),
+    )
+    .await;
+
+    let jwt = |roles: Vec<&str>| {
+        let claims = StandardClaims {
+            realm_access: Some(Access {
+                roles: roles.into_iter().map(str::to_owned).collect(),
+            }),
+            ..StandardClaims::default()
+        };
+        encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    };
+
+    // Authentication only
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec![]))))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Role check
+    let req = test::TestRequest::with_uri("/private/admin")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec!["admin"]))))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/private/admin")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec!["user"]))))
+        .insert_header(("X-Request-Id", "some-request-id"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer error="insufficient_scope", error_description="JWT token is missing roles: any(admin, client1.admin)""#
+    );
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Value>(&body).unwrap(),
+        json!({
+            "type": "urn:actix-web-middleware-keycloak-auth:error:missing_roles",
+            "title": "Forbidden",
+            "status": 403,
+            "code": "missing_roles",
+            "detail": "JWT token is missing roles: any(admin, client1.admin)",
+            "missing_roles": ["any(admin, client1.admin)"],
+            "correlation_id": "some-request-id",
+        })
+    );
+
+    let req = test::TestRequest::with_uri("/private/admin").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Guard
+    let req = test::TestRequest::with_uri("/private/guarded")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec!["admin"]))))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/private/guarded")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec!["user"]))))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn per_route_role_check_after_passthrough() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+            .with_passthrough_policy(AlwaysPassPolicy);
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(hello_world))
+                .service(
+                    web::resource("/admin")
+                        .wrap(RoleCheck::new(RoleExpr::realm("admin")))
+                        .route(web::get().to(hello_world)),
+                ),
+        ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::with_uri("/private/admin").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from_static(b"No bearer token was provided (tried: Authorization header)")
+    );
+}
+
+#[actix_web::test]
+async fn per_route_checks_without_middleware() {
+    init_logger();
+
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::resource("/not-admin")
+                    .wrap(RoleCheck::new(RoleExpr::not(RoleExpr::realm("admin"))))
+                    .route(web::get().to(hello_world)),
+            )
+            .service(
+                web::resource("/no-permissions")
+                    .wrap(PermissionCheck::new(vec![]))
+                    .route(web::get().to(hello_world)),
+            )
+            .route("/admin", web::get().to(admin)),
+    )
+    .await;
+
+    for uri in ["/not-admin", "/no-permissions", "/admin"] {
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", uri);
+        assert!(resp.headers().get("WWW-Authenticate").is_none(), "{}", uri);
+    }
+}
+
+role_requirement!(AdminRole = "any(admin, client1.admin)");
+
+async fn admin(roles: RequireRoles<AdminRole>) -> impl Responder {
+    HttpResponse::Ok().body(format!("{} roles", roles.len()))
+}
+
+#[actix_web::test]
+async fn require_roles_extractor() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        response_format: ResponseFormat::ProblemJson,
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("/admin", web::get().to(admin)),
+        ),
+    )
+    .await;
+
+    let jwt = |roles: Vec<&str>| {
+        let claims = StandardClaims {
+            realm_access: Some(Access {
+                roles: roles.into_iter().map(str::to_owned).collect(),
+            }),
+            ..StandardClaims::default()
+        };
+        encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    };
+
+    let req = test::TestRequest::with_uri("/private/admin")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", &jwt(vec!["admin", "user"])),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from_static(b"2 roles"));
+
+    let req = test::TestRequest::with_uri("/private/admin")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(vec!["user"]))))
+        .insert_header(("X-Request-Id", "some-request-id"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        resp.headers().get("Content-Type").unwrap(),
+        "application/problem+json"
+    );
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer error="insufficient_scope", error_description="JWT token is missing roles: any(admin, client1.admin)""#
+    );
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Value>(&body).unwrap(),
+        json!({
+            "type": "urn:actix-web-middleware-keycloak-auth:error:missing_roles",
+            "title": "Forbidden",
+            "status": 403,
+            "code": "missing_roles",
+            "detail": "JWT token is missing roles: any(admin, client1.admin)",
+            "missing_roles": ["any(admin, client1.admin)"],
+            "correlation_id": "some-request-id",
+        })
+    );
+}
+
+#[actix_web::test]
+async fn role_mapping() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        role_mapping: Some(
+            RoleMapping::from_json(r#"{"admin": ["client1.admin"], "client1.admin": ["user"]}"#)
+                .unwrap(),
+        ),
+        required_roles: vec![Role::Realm {
+            role: "user".to_owned(),
+        }],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(roles)),
+        ),
+    )
+    .await;
+
+    let claims = StandardClaims {
+        realm_access: Some(Access {
+            roles: vec!["admin".to_owned()],
+        }),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Vec<Role>>(&body).unwrap(),
+        vec![
+            Role::Realm {
+                role: "admin".to_owned(),
+            },
+            Role::Client {
+                client: "client1".to_owned(),
+                role: "admin".to_owned(),
+            },
+            Role::Realm {
+                role: "user".to_owned(),
+            },
+        ]
+    );
+
+    let claims = StandardClaims {
+        realm_access: Some(Access {
+            roles: vec!["other".to_owned()],
+        }),
+        ..StandardClaims::default()
+    };
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+async fn groups(groups: KeycloakGroups) -> impl Responder {
+    HttpResponse::Ok().body(format!(
+        "{} {}",
+        groups.len(),
+        groups.is_member_of("/org/team-a")
+    ))
+}
+
+#[actix_web::test]
+async fn required_groups() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        required_groups: vec!["/org".to_owned()],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(groups)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        (json!(["/org/team-a", "/admins"]), StatusCode::OK, "2 true"),
+        (json!(["/org"]), StatusCode::OK, "1 false"),
+        (
+            json!(["/organization"]),
+            StatusCode::FORBIDDEN,
+            "JWT token is missing groups: /org",
+        ),
+        (
+            Value::Null,
+            StatusCode::FORBIDDEN,
+            "JWT token is missing groups: /org",
+        ),
+    ];
+    for (groups, status, body) in cases {
+        let mut claims = to_value(StandardClaims::default()).unwrap();
+        if !groups.is_null() {
+            claims["groups"] = groups;
+        }
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        let res_body = test::read_body(resp).await;
+        assert_eq!(res_body, Bytes::from(body));
+    }
+}
+
+#[actix_web::test]
+async fn invalid_groups() {
+    init_logger();
+
+    let jwt = |groups: Value| {
+        let mut claims = to_value(StandardClaims::default()).unwrap();
+        claims["groups"] = groups;
+        encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    };
+    let app = |required_groups: Vec<String>| {
+        let keycloak_auth = KeycloakAuth {
+            required_groups,
+            ..KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            )
+        };
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private))
+                .route("/groups", web::get().to(groups)),
+        )
+    };
+
+    // Groups are not required: the claim is ignored (but cannot be extracted)
+    let app_without_requirement = test::init_service(app(vec![])).await;
+    for groups in [Value::Null, json!("/org"), json!([{ "path": "/org" }])] {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt(groups.clone()))))
+            .to_request();
+        let resp = test::call_service(&app_without_requirement, req).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", groups);
+    }
+    let req = test::TestRequest::with_uri("/private/groups")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(json!("/org")))))
+        .to_request();
+    let resp = test::call_service(&app_without_requirement, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Groups are required: the claim must be valid
+    let app_with_requirement = test::init_service(app(vec!["/org".to_owned()])).await;
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt(json!("/org")))))
+        .to_request();
+    let resp = test::call_service(&app_with_requirement, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+async fn scopes(scopes: KeycloakScopes) -> impl Responder {
+    HttpResponse::Ok().body(format!("{} {}", scopes.join(","), scopes.has("profile")))
+}
+
+#[actix_web::test]
+async fn required_scopes() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        required_scopes: vec!["orders:read".to_owned()],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(scopes)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        (
+            Some(json!("openid orders:read")),
+            StatusCode::OK,
+            "openid,orders:read false",
+        ),
+        (
+            Some(json!("profile")),
+            StatusCode::FORBIDDEN,
+            "JWT token is missing scopes: orders:read",
+        ),
+        (
+            Some(json!(["openid", "orders:read"])),
+            StatusCode::OK,
+            "openid,orders:read false",
+        ),
+        (
+            None,
+            StatusCode::FORBIDDEN,
+            "JWT token is missing scopes: orders:read",
+        ),
+    ];
+    for (scope, status, body) in cases {
+        let mut claims = to_value(StandardClaims::default()).unwrap();
+        if let Some(scope) = scope {
+            claims["scope"] = scope;
+        }
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        if status == StatusCode::FORBIDDEN {
+            assert_eq!(
+                resp.headers().get("WWW-Authenticate").unwrap(),
+                r#"Bearer error="insufficient_scope", error_description="JWT token is missing scopes: orders:read", scope="orders:read""#
+            );
+        }
+        let res_body = test::read_body(resp).await;
+        assert_eq!(res_body, Bytes::from(body));
+    }
+}
+
+#[actix_web::test]
+async fn invalid_scopes() {
+    init_logger();
+
+    let mut claims = to_value(StandardClaims::default()).unwrap();
+    claims["scope"] = json!({ "orders": "read" });
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let app = |required_scopes: Vec<String>| {
+        let keycloak_auth = KeycloakAuth {
+            required_scopes,
+            ..KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            )
+        };
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private))
+                .route("/scopes", web::get().to(scopes)),
+        )
+    };
+
+    // Scopes are not required: the claim is ignored (but cannot be extracted)
+    let app_without_requirement = test::init_service(app(vec![])).await;
+    let cases = [
+        ("/private", StatusCode::OK),
+        ("/private/scopes", StatusCode::FORBIDDEN),
+    ];
+    for (uri, status) in cases {
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app_without_requirement, req).await;
+        assert_eq!(resp.status(), status, "{}", uri);
+    }
+
+    // Scopes are required: the claim must be valid
+    let app_with_requirement = test::init_service(app(vec!["orders:read".to_owned()])).await;
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app_with_requirement, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn custom_role_sources() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        role_sources: vec![
+            RoleSource::Realm("/https:~1~1example.com~1roles".to_owned()),
+            RoleSource::Client("client1".to_owned(), "/client_roles".to_owned()),
+        ],
+        required_roles: vec![
+            Role::Realm {
+                role: "admin".to_owned(),
+            },
+            Role::Client {
+                client: "client1".to_owned(),
+                role: "reader".to_owned(),
+            },
+        ],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(roles)),
+        ),
+    )
+    .await;
+
+    let mut claims = to_value(StandardClaims {
+        realm_access: Some(Access {
+            roles: vec!["ignored".to_owned()],
+        }),
+        ..StandardClaims::default()
+    })
+    .unwrap();
+    claims["https://example.com/roles"] = json!(["admin"]);
+    claims["client_roles"] = json!(["reader"]);
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        from_slice::<Vec<Role>>(&body).unwrap(),
+        vec![
+            Role::Realm {
+                role: "admin".to_owned(),
+            },
+            Role::Client {
+                client: "client1".to_owned(),
+                role: "reader".to_owned(),
+            },
+        ]
+    );
+
+    claims["client_roles"] = json!("reader");
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+fn rpt(permissions: Option<Value>) -> String {
+    let mut claims = to_value(StandardClaims::default()).unwrap();
+    if let Some(permissions) = permissions {
+        claims["authorization"] = json!({ "permissions": permissions });
+    }
+    encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap()
+}
+
+async fn permissions(permissions: KeycloakPermissions) -> impl Responder {
+    HttpResponse::Ok().body(format!(
+        "{} {}",
+        permissions
+            .iter()
+            .filter_map(|p| p.rsname.clone())
+            .collect::<Vec<String>>()
+            .join(","),
+        permissions.has("orders", Some("write"))
+    ))
+}
+
+#[actix_web::test]
+async fn required_permissions() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth {
+        required_permissions: vec![PermissionRequirement::resource_scope("orders", "read")],
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(permissions)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        (
+            Some(json!([
+                { "rsid": "1b2c", "rsname": "orders", "scopes": ["read", "write"] },
+                { "rsid": "3d4e", "rsname": "invoices" },
+            ])),
+            StatusCode::OK,
+            "orders,invoices true",
+        ),
+        (
+            Some(json!([{ "rsid": "1b2c", "rsname": "orders", "scopes": ["write"] }])),
+            StatusCode::FORBIDDEN,
+            "JWT token is missing permissions: orders#read",
+        ),
+        (
+            Some(json!([])),
+            StatusCode::FORBIDDEN,
+            "JWT token is missing permissions: orders#read",
+        ),
+        (
+            None,
+            StatusCode::FORBIDDEN,
+            "JWT token is missing permissions: orders#read",
+        ),
+    ];
+    for (permissions, status, body) in cases {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &rpt(permissions))))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        let res_body = test::read_body(resp).await;
+        assert_eq!(res_body, Bytes::from(body));
+    }
+}
+
+#[actix_web::test]
+async fn invalid_permissions() {
+    init_logger();
+
+    let jwt = rpt(Some(json!("orders")));
+    let app = |required_permissions: Vec<PermissionRequirement>| {
+        let keycloak_auth = KeycloakAuth {
+            required_permissions,
+            ..KeycloakAuth::default_with_pk(
+                DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap(),
+            )
+        };
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private))
+                .route("/permissions", web::get().to(permissions))
+                .service(
+                    web::resource("/orders")
+                        .wrap(PermissionCheck::new(vec![PermissionRequirement::resource(
+                            "orders",
+                        )]))
+                        .route(web::get().to(private)),
+                ),
+        )
+    };
+
+    // Permissions are not required by the middleware: the claim is ignored (but cannot be extracted, nor satisfy per-route checks)
+    let app_without_requirement = test::init_service(app(vec![])).await;
+    let cases = [
+        ("/private", StatusCode::OK),
+        ("/private/permissions", StatusCode::FORBIDDEN),
+        ("/private/orders", StatusCode::FORBIDDEN),
+    ];
+    for (uri, status) in cases {
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app_without_requirement, req).await;
+        assert_eq!(resp.status(), status, "{}", uri);
+    }
+
+    // Permissions are required: the claim must be valid
+    let app_with_requirement =
+        test::init_service(app(vec![PermissionRequirement::resource("orders")])).await;
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+        .to_request();
+    let resp = test::call_service(&app_with_requirement, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn per_route_permission_checks() {
+    init_logger();
+
+    let keycloak_auth =
+        KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+            .with_passthrough_policy(AlwaysPassPolicy);
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private").wrap(keycloak_auth).service(
+                web::scope("/orders")
+                    .wrap(PermissionCheck::new(vec![PermissionRequirement::resource(
+                        "1b2c",
+                    )]))
+                    .route(
+                        "",
+                        web::get().to(|| async { HttpResponse::Ok().body("Orders") }),
+                    )
+                    .service(
+                        web::resource("/edit")
+                            .wrap(PermissionCheck::new(vec![
+                                PermissionRequirement::resource_scope("orders", "write"),
+                            ]))
+                            .route(web::post().to(|| async { HttpResponse::Ok().body("Edited") })),
+                    ),
+            ),
+        ),
+    )
+    .await;
+
+    let reader = rpt(Some(json!([
+        { "rsid": "1b2c", "rsname": "orders", "scopes": ["read"] },
+    ])));
+    let writer = rpt(Some(json!([
+        { "rsid": "1b2c", "rsname": "orders", "scopes": ["read", "write"] },
+    ])));
+    let other = rpt(Some(json!([
+        { "rsid": "3d4e", "rsname": "invoices", "scopes": ["read", "write"] },
+    ])));
+    let cases = [
+        (
+            &reader,
+            test::TestRequest::get(),
+            "/private/orders",
+            StatusCode::OK,
+            "Orders",
+        ),
+        (
+            &other,
+            test::TestRequest::get(),
+            "/private/orders",
+            StatusCode::FORBIDDEN,
+            "JWT token is missing permissions: 1b2c",
+        ),
+        (
+            &writer,
+            test::TestRequest::post(),
+            "/private/orders/edit",
+            StatusCode::OK,
+            "Edited",
+        ),
+        (
+            &reader,
+            test::TestRequest::post(),
+            "/private/orders/edit",
+            StatusCode::FORBIDDEN,
+            "JWT token is missing permissions: orders#write",
+        ),
+    ];
+    for (jwt, req, uri, status, body) in cases {
+        let req = req
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        let res_body = test::read_body(resp).await;
+        assert_eq!(res_body, Bytes::from(body));
+    }
+
+    // Authentication failed but the request was let through: the authentication error is returned
+    let req = test::TestRequest::with_uri("/private/orders").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+async fn subject(claims: UnstructuredKeycloakClaims) -> impl Responder {
+    HttpResponse::Ok().body(claims.get::<String>("sub").unwrap())
+}
+
+/// An HTTP client that answers introspection requests: only the `active-token` token is active
+struct StubIntrospectionClient;
+
+impl HttpClient for StubIntrospectionClient {
+    fn get(
+        &self,
+        url: &str,
+    ) -> futures_util::future::LocalBoxFuture<'static, Result<String, String>> {
+        Box::pin(futures_util::future::ready(Err(format!(
+            "Unexpected URL: {}",
+            url
+        ))))
+    }
+
+    fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+    ) -> futures_util::future::LocalBoxFuture<'static, Result<String, String>> {
+        let form: HashMap<&str, &str> = form.iter().copied().collect();
+        let response = if url
+            != "https://sso.example.com/realms/test/protocol/openid-connect/token/introspect"
+            || form.get("client_secret") != Some(&"secret")
+        {
+            Err("401 Unauthorized".to_owned())
+        } else if form.get("token") == Some(&"active-token") {
+            Ok(json!({
+                "active": true,
+                "sub": "13b9bb9e-e2fa-4ccf-a4c3-4ecc8b1b3a44",
+                "iss": "https://sso.example.com/realms/test",
+                "realm_access": { "roles": ["admin"] },
+            })
+            .to_string())
+        } else {
+            Ok(json!({ "active": false }).to_string())
+        };
+        Box::pin(futures_util::future::ready(response))
+    }
+}
+
+#[actix_web::test]
+async fn introspection() {
+    init_logger();
+
+    let introspection = |client_secret: &str| {
+        Introspection::with_client(
+            &Introspection::keycloak_endpoint("https://sso.example.com/realms/test"),
+            "api",
+            client_secret,
+            StubIntrospectionClient,
+        )
+    };
+    let app = test::init_service(
+        App::new()
+            .service(
+                web::scope("/private")
+                    .wrap(KeycloakAuth {
+                        issuers: vec!["https://sso.example.com/realms/test".to_owned()],
+                        required_roles: vec![Role::Realm {
+                            role: "admin".to_owned(),
+                        }],
+                        ..KeycloakAuth::default_with_introspection(introspection("secret"))
+                    })
+                    .route("", web::get().to(subject)),
+            )
+            .service(
+                web::scope("/misconfigured")
+                    .wrap(KeycloakAuth::default_with_introspection(introspection(
+                        "wrong",
+                    )))
+                    .route("", web::get().to(subject)),
+            ),
+    )
+    .await;
+
+    let cases = [
+        (
+            "/private",
+            "active-token",
+            StatusCode::OK,
+            "13b9bb9e-e2fa-4ccf-a4c3-4ecc8b1b3a44",
+        ),
+        (
+            "/private",
+            "revoked-token",
+            StatusCode::UNAUTHORIZED,
+            "Token is not active",
+        ),
+        (
+            "/misconfigured",
+            "active-token",
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Error while introspecting token (401 Unauthorized)",
+        ),
+    ];
+    for (uri, token, status, body) in cases {
+        let req = test::TestRequest::with_uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        let res_body = test::read_body(resp).await;
+        assert_eq!(res_body, Bytes::from(body));
+    }
+}
+
+#[actix_web::test]
+async fn introspection_with_get_only_client() {
+    init_logger();
+
+    let keycloak_auth = KeycloakAuth::default_with_introspection(Introspection::with_client(
+        "https://sso.example.com/realms/test/protocol/openid-connect/token/introspect",
+        "api",
+        "secret",
+        stub_discovery_client("https://sso.example.com/realms/test"),
+    ));
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(subject)),
+        ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer active-token"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().get("WWW-Authenticate").is_none());
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from("Error while introspecting token (This HTTP client cannot send POST requests (https://sso.example.com/realms/test/protocol/openid-connect/token/introspect))")
+    );
+}
+
+#[cfg(feature = "http_client")]
+#[actix_web::test]
+async fn default_http_client_timeout() {
+    init_logger();
+
+    let server = actix_web::HttpServer::new(|| {
+        App::new().route(
+            "/slow",
+            web::get().to(|| async {
+                actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+                HttpResponse::Ok().body("too late")
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let server_handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let client = actix_web_middleware_keycloak_auth::DefaultHttpClient::with_timeout(
+        Duration::from_millis(100),
+    );
+    let start = std::time::Instant::now();
+    let response = client.get(&format!("http://{}/slow", address)).await;
+    assert!(response.is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    server_handle.stop(false).await;
+}
+
+#[cfg(feature = "http_client")]
+#[actix_web::test]
+async fn introspection_from_stub_server() {
+    init_logger();
+
+    #[derive(Deserialize)]
+    struct IntrospectionForm {
+        token: String,
+        client_id: String,
+        client_secret: String,
+    }
+
+    let server = actix_web::HttpServer::new(|| {
+        App::new().route(
+            "/realms/test/protocol/openid-connect/token/introspect",
+            web::post().to(|form: web::Form<IntrospectionForm>| async move {
+                if form.client_id != "api" || form.client_secret != "secret" {
+                    return HttpResponse::Unauthorized().finish();
+                }
+                HttpResponse::Ok().json(if form.token == "active-token" {
+                    json!({ "active": true, "sub": "13b9bb9e-e2fa-4ccf-a4c3-4ecc8b1b3a44" })
+                } else {
+                    json!({ "active": false })
+                })
+            }),
+        )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let address = server.addrs()[0];
+    let server = server.run();
+    let server_handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let keycloak_auth = KeycloakAuth::default_with_introspection(Introspection::new(
+        &Introspection::keycloak_endpoint(&format!("http://{}/realms/test", address)),
+        "api",
+        "secret",
+    ));
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(subject)),
+        ),
+    )
+    .await;
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer active-token"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    let body = test::read_body(resp).await;
+    assert_eq!(body, Bytes::from("13b9bb9e-e2fa-4ccf-a4c3-4ecc8b1b3a44"));
+
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer revoked-token"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    server_handle.stop(false).await;
+}
+
+/// An HTTP client that counts introspection requests, and answers that tokens are active until the provided expiration date
+struct CountingIntrospectionClient {
+    calls: Arc<AtomicUsize>,
+    exp: i64,
+}
+
+impl HttpClient for CountingIntrospectionClient {
+    fn get(
+        &self,
+        url: &str,
+    ) -> futures_util::future::LocalBoxFuture<'static, Result<String, String>> {
+        Box::pin(futures_util::future::ready(Err(format!(
+            "Unexpected URL: {}",
+            url
+        ))))
+    }
+
+    fn post_form(
+        &self,
+        _url: &str,
+        form: &[(&str, &str)],
+    ) -> futures_util::future::LocalBoxFuture<'static, Result<String, String>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let token = form
+            .iter()
+            .find(|(name, _)| *name == "token")
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default();
+        Box::pin(futures_util::future::ready(Ok(json!({
+            "active": true,
+            "sub": token,
+            "exp": self.exp,
+        })
+        .to_string())))
+    }
+}
+
+#[actix_web::test]
+async fn validation_cache() {
+    init_logger();
+
+    let exp = 1_600_000_000;
+    let calls = Arc::new(AtomicUsize::new(0));
+    let clock = FixedClock::new(Utc.timestamp_opt(exp - 120, 0).unwrap());
+    let cache = Arc::new(InMemoryValidationCache::new(10, Duration::from_secs(60)));
+    let keycloak_auth = KeycloakAuth {
+        clock: Arc::new(clock.clone()),
+        validation_cache: Some(cache.clone()),
+        ..KeycloakAuth::default_with_introspection(Introspection::with_client(
+            "https://sso.example.com/realms/test/protocol/openid-connect/token/introspect",
+            "api",
+            "secret",
+            CountingIntrospectionClient {
+                calls: Arc::clone(&calls),
+                exp,
+            },
+        ))
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(subject)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        // First request: the token is introspected
+        (0, "token-a", 1),
+        // Cached
+        (30, "token-a", 1),
+        // Another token
+        (30, "token-b", 2),
+        // Capped by the TTL of the cache
+        (60, "token-a", 3),
+        (90, "token-a", 3),
+        // Capped by the expiration date of the token
+        (120, "token-a", 4),
+    ];
+    for (elapsed, token, expected_calls) in cases {
+        clock.set(Utc.timestamp_opt(exp - 120 + elapsed, 0).unwrap());
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, Bytes::from(token));
+        assert_eq!(calls.load(Ordering::SeqCst), expected_calls);
+    }
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 4));
+}
+
+#[actix_web::test]
+async fn validation_cache_checks_time_claims() {
+    init_logger();
+
+    let clock = FixedClock::new(Utc.timestamp_opt(1_600_000_000, 0).unwrap());
+    let cache = Arc::new(InMemoryValidationCache::new(10, Duration::from_secs(3600)));
+    let keycloak_auth = KeycloakAuth {
+        clock: Arc::new(clock.clone()),
+        max_age: Some(Duration::from_secs(60)),
+        validation_cache: Some(cache.clone()),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(subject)),
+        ),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let claims = json!({ "sub": user_id, "iat": 1_600_000_000, "exp": 1_600_003_600 });
+    let jwt = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    for (elapsed, status) in [
+        (0, StatusCode::OK),
+        (30, StatusCode::OK),
+        (200, StatusCode::UNAUTHORIZED),
+    ] {
+        clock.set(Utc.timestamp_opt(1_600_000_000 + elapsed, 0).unwrap());
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status);
+    }
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+}
+
+#[actix_web::test]
+async fn revocation_list() {
+    init_logger();
+
+    let revocation_list = RevocationList::default();
+    let keycloak_auth = KeycloakAuth {
+        revocation_checker: Some(Arc::new(revocation_list.clone())),
+        validation_cache: Some(Arc::new(InMemoryValidationCache::new(
+            10,
+            Duration::from_secs(60),
+        ))),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    let jwt = |claims: &StandardClaims, sid: &str| {
+        let mut claims = to_value(claims).unwrap();
+        claims["sid"] = json!(sid);
+        encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    };
+    let user = StandardClaims::default();
+    let user_token = jwt(&user, "session-1");
+    let user_other_token = jwt(
+        &StandardClaims {
+            jti: Uuid::new_v4(),
+            ..user.clone()
+        },
+        "session-2",
+    );
+    let other_user_token = jwt(
+        &StandardClaims {
+            sub: Uuid::new_v4(),
+            jti: Uuid::new_v4(),
+            ..StandardClaims::default()
+        },
+        "session-1",
+    );
+
+    let call = |jwt: String| {
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", jwt)))
+            .to_request();
+        test::call_service(&app, req)
+    };
+
+    // The validation result of the token is cached, but revocation is still checked
+    assert_eq!(call(user_token.clone()).await.status(), StatusCode::OK);
+    revocation_list.revoke_token(&user.jti.to_string(), user.exp);
+    let resp = call(user_token.clone()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        resp.headers().get("WWW-Authenticate").unwrap(),
+        r#"Bearer error="invalid_token", error_description="Token has been revoked (the token was revoked)""#
+    );
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from("Token has been revoked (the token was revoked)")
+    );
+    assert_eq!(
+        call(user_other_token.clone()).await.status(),
+        StatusCode::OK
+    );
+
+    revocation_list.revoke_session("session-1", user.exp);
+    let resp = call(other_user_token.clone()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from("Token has been revoked (the session was revoked)")
+    );
+
+    revocation_list.revoke_subject(&user.sub.to_string());
+    let resp = call(user_other_token.clone()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert_eq!(
+        body,
+        Bytes::from("Token has been revoked (the subject was revoked)")
+    );
+
+    revocation_list.restore_subject(&user.sub.to_string());
+    assert_eq!(call(user_other_token).await.status(), StatusCode::OK);
+
+    revocation_list.restore_session("session-1");
+    assert_eq!(call(other_user_token).await.status(), StatusCode::OK);
+    revocation_list.restore_token(&user.jti.to_string());
+    assert_eq!(call(user_token).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn custom_revocation_checker() {
+    init_logger();
+
+    let checks = Arc::new(AtomicUsize::new(0));
+    let checks_clone = Arc::clone(&checks);
+    let keycloak_auth = KeycloakAuth {
+        revocation_checker: Some(Arc::new(move |claims: &Value| {
+            checks_clone.fetch_add(1, Ordering::SeqCst);
+            let is_revoked = claims.get("azp").and_then(Value::as_str) == Some("leaked-client");
+            async move {
+                actix_web::rt::task::yield_now().await;
+                is_revoked.then(|| "the client leaked its tokens".to_owned())
+            }
+        })),
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        ("client", StatusCode::OK),
+        ("leaked-client", StatusCode::UNAUTHORIZED),
+    ];
+    for (azp, status) in cases {
+        let claims = StandardClaims {
+            azp: azp.to_owned(),
+            ..StandardClaims::default()
+        };
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}", jwt_with_kid("key", &claims)),
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status);
+    }
+    assert_eq!(checks.load(Ordering::SeqCst), 2);
+
+    // Revocation is checked after the token is validated
+    let req = test::TestRequest::with_uri("/private")
+        .insert_header(("Authorization", "Bearer invalid"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(checks.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn claim_validators() {
+    init_logger();
+
+    let calls = Arc::new(Mutex::new(vec![]));
+    let tenant_calls = Arc::clone(&calls);
+    let tenant_validator = move |claims: &Value, req: &ServiceRequest| {
+        tenant_calls.lock().unwrap().push("tenant");
+        let tenant = claims
+            .get("tenant_id")
+            .and_then(Value::as_str)
+            .map(str::to_owned);
+        let host = req.connection_info().host().to_owned();
+        async move {
+            actix_web::rt::task::yield_now().await;
+            match tenant {
+                Some(tenant) if host == format!("{}.example.com", tenant) => Ok(()),
+                _ => Err(AuthError::custom(
+                    "wrong_tenant",
+                    "JWT token belongs to another tenant",
+                )),
+            }
+        }
+    };
+    let plan_calls = Arc::clone(&calls);
+    let plan_validator = move |claims: &Value, _req: &ServiceRequest| {
+        plan_calls.lock().unwrap().push("plan");
+        let is_paying = claims.get("plan").and_then(Value::as_str) != Some("free");
+        async move {
+            if is_paying {
+                Ok(())
+            } else {
+                Err(AuthError::custom(
+                    "payment_required",
+                    "The plan of the tenant is free",
+                ))
+            }
+        }
+    };
+    let validators: Vec<Arc<dyn ClaimValidator>> =
+        vec![Arc::new(tenant_validator), Arc::new(plan_validator)];
+
+    let keycloak_auth = KeycloakAuth {
+        response_format: ResponseFormat::ProblemJson,
+        required_roles: vec![Role::Realm {
+            role: "user".to_owned(),
+        }],
+        claim_validators: validators,
+        ..KeycloakAuth::default_with_pk(DecodingKey::from_rsa_pem(KEYCLOAK_PK.as_bytes()).unwrap())
+    };
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/private")
+                .wrap(keycloak_auth)
+                .route("", web::get().to(private)),
+        ),
+    )
+    .await;
+
+    let cases = [
+        (
+            "acme.example.com",
+            json!({ "tenant_id": "acme", "plan": "pro", "realm_access": { "roles": ["user"] } }),
+            StatusCode::OK,
+            None,
+            vec!["tenant", "plan"],
+        ),
+        (
+            "other.example.com",
+            json!({ "tenant_id": "acme", "plan": "free", "realm_access": { "roles": ["user"] } }),
+            StatusCode::FORBIDDEN,
+            Some("wrong_tenant"),
+            vec!["tenant"],
+        ),
+        (
+            "acme.example.com",
+            json!({ "tenant_id": "acme", "plan": "free", "realm_access": { "roles": ["user"] } }),
+            StatusCode::FORBIDDEN,
+            Some("payment_required"),
+            vec!["tenant", "plan"],
+        ),
+        // Validators run after roles are checked
+        (
+            "acme.example.com",
+            json!({ "tenant_id": "acme", "realm_access": { "roles": [] } }),
+            StatusCode::FORBIDDEN,
+            Some("missing_roles"),
+            vec![],
+        ),
+    ];
+    for (host, extra_claims, status, code, expected_calls) in cases {
+        calls.lock().unwrap().clear();
+        let mut claims = to_value(StandardClaims::default()).unwrap();
+        for (key, value) in extra_claims.as_object().unwrap() {
+            claims[key] = value.clone();
+        }
+        let jwt = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(KEYCLOAK_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let req = test::TestRequest::with_uri("/private")
+            .insert_header(("Host", host))
+            .insert_header(("Authorization", format!("Bearer {}", &jwt)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), status);
+        if let Some(code) = code {
+            let body: Value = from_slice(&test::read_body(resp).await).unwrap();
+            assert_eq!(body["code"], json!(code));
+        }
+        assert_eq!(*calls.lock().unwrap(), expected_calls);
+    }
+}